@@ -0,0 +1,20 @@
+// Передает в сборку хэш коммита для /version.
+
+use std::process::Command;
+
+fn main()
+{
+    // Сборка без .git, например в Docker, может передать коммит через переменную окружения.
+    let commit = std::env::var("SECRET_SANTA_GIT_COMMIT").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    println!("cargo:rustc-env=GIT_COMMIT={}", commit.unwrap_or_else(|| "unknown".to_string()));
+    println!("cargo:rerun-if-env-changed=SECRET_SANTA_GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -19,6 +19,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let address = address.lines().next().unwrap().to_string();
     println!("Server: {}", address);
 
+    // Токен пользователя для заголовка Authorization, выдается при создании пользователя
+    let mut token = String::new();
+
     let mut iteration = 0;
 
     loop {
@@ -107,6 +110,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::io::stdin().read_line(&mut json)?;
         let json = json.trim().to_string();
 
+        // Ввод токена, пустая строка оставляет прежний
+        let mut new_token = String::new();
+        println!("Enter bearer token, empty keeps the current one:");
+        std::io::stdin().read_line(&mut new_token)?;
+        if !new_token.trim().is_empty() {
+            token = new_token.trim().to_string();
+        }
+
         // Отправка запроса
         let mut req = hyper::Request::new(hyper::Body::from(json));
         *req.method_mut() = match METHODS[method_num] {
@@ -130,6 +141,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         };
 
+        if !token.is_empty() {
+            req.headers_mut().insert(hyper::header::AUTHORIZATION, format!("Bearer {}", token).parse()?);
+        }
+
         // Вывод запроса
         println!("====================");
         println!("Request: {}", req.method());
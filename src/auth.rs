@@ -0,0 +1,284 @@
+// Аутентификация по токену из заголовка `Authorization: Bearer <token>` или по cookie сессии.
+//
+// Токен выдается при создании пользователя, сессия - при входе по логину и паролю. Middleware
+// подключается к маршрутам, которым нужен действующий пользователь, и кладет его ID
+// в расширения запроса.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use sha2::Sha256;
+use tide::{Middleware, Next, Request, Response};
+use crate::{DataBase, Id};
+
+pub const SESSION_COOKIE: &str = "session";
+pub const SESSION_LIFETIME_DAYS: i64 = 7;
+pub const MIN_PASSWORD_LEN: usize = 8;
+const PASSWORD_HASH_ROUNDS: u32 = 100_000;
+
+#[derive(Clone, Copy)]
+pub struct AuthUser(pub Id);
+
+// Логин и соленый хэш пароля пользователя.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Credentials
+{
+    pub login: String,
+    salt: String,
+    hash: String,
+}
+
+impl Credentials
+{
+    pub fn new(login: String, password: &str) -> Credentials
+    {
+        let salt = random_hex(16);
+        let hash = hash_password(password, &salt);
+        Credentials { login, salt, hash }
+    }
+
+    pub fn verify(&self, password: &str) -> bool
+    {
+        let hash = hash_password(password, &self.salt);
+        // Сравнение без раннего выхода, чтобы время ответа не выдавало совпавший префикс.
+        hash.len() == self.hash.len() && hash.bytes().zip(self.hash.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Session
+{
+    pub user_id: Id,
+    pub expires: DateTime<Utc>,
+}
+
+impl Session
+{
+    pub fn new(user_id: Id) -> Session
+    {
+        Session {
+            user_id,
+            expires: Utc::now() + Duration::days(SESSION_LIFETIME_DAYS),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool
+    {
+        self.expires <= Utc::now()
+    }
+}
+
+pub struct Authentication;
+
+fn response_unauthorized(msg: &str) -> Response
+{
+    let mut response = crate::response_error_with_status(401, msg);
+    response.insert_header("WWW-Authenticate", "Bearer");
+    response
+}
+
+#[tide::utils::async_trait]
+impl Middleware<Arc<Mutex<DataBase>>> for Authentication
+{
+    async fn handle(&self, mut request: Request<Arc<Mutex<DataBase>>>, next: Next<'_, Arc<Mutex<DataBase>>>) -> tide::Result
+    {
+        let token = bearer_token(&request);
+        let session = session_id(&request);
+
+        let user_id = {
+            let guard = request.state().lock().unwrap();
+            match (token, session)
+            {
+                (Some(token), _) => guard.tokens.get(&token).copied().ok_or("invalid token"),
+                (None, Some(session)) => match guard.sessions.get(&session)
+                {
+                    None => Err("invalid session"),
+                    Some(session) if session.is_expired() => Err("session expired"),
+                    Some(session) => Ok(session.user_id),
+                },
+                (None, None) => Err("authentication required"),
+            }
+        };
+        match user_id
+        {
+            Err(msg) => Ok(response_unauthorized(msg)),
+            Ok(user_id) =>
+            {
+                request.set_ext(AuthUser(user_id));
+                let mut response = next.run(request).await;
+                // Для журнала запросов.
+                response.insert_ext(AuthUser(user_id));
+                Ok(response)
+            }
+        }
+    }
+}
+
+// ID пользователя, от имени которого выполняется запрос. Только для маршрутов с Authentication.
+pub fn acting_user<State>(request: &Request<State>) -> Id
+{
+    request.ext::<AuthUser>().expect("route without Authentication middleware").0
+}
+
+// Токен из заголовка `Authorization: Bearer <token>`, если он есть.
+pub fn bearer_token<State>(request: &Request<State>) -> Option<String>
+{
+    request.header("Authorization")
+        .and_then(|values| values.as_str().strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+// IP адрес клиента без порта: у каждого соединения порт свой. Берется из TrustedProxies, а без
+// него - адрес самого соединения.
+pub fn client_address<State>(request: &Request<State>) -> String
+{
+    match request.ext::<ClientAddress>()
+    {
+        Some(ClientAddress(Some(address))) => address.to_string(),
+        _ => match request.peer_addr().unwrap_or("unknown").parse::<SocketAddr>()
+        {
+            Ok(address) => address.ip().to_string(),
+            Err(_) => request.peer_addr().unwrap_or("unknown").to_string(),
+        },
+    }
+}
+
+// Определяет адрес клиента для client_address. Заголовок X-Forwarded-For клиент может подделать,
+// поэтому он читается, только если соединение пришло от балансировщика из trusted_proxies, и
+// только справа налево до первого адреса не из них.
+pub struct TrustedProxies
+{
+    trusted_proxies: Vec<IpAddr>,
+}
+
+// Адрес клиента, определенный TrustedProxies, в расширениях запроса.
+#[derive(Clone)]
+struct ClientAddress(Option<IpAddr>);
+
+impl TrustedProxies
+{
+    pub fn new(trusted_proxies: Vec<IpAddr>) -> TrustedProxies
+    {
+        TrustedProxies { trusted_proxies }
+    }
+
+    fn resolve<State>(&self, request: &Request<State>) -> Option<IpAddr>
+    {
+        let mut address = request.peer_addr()?.parse::<SocketAddr>().ok()?.ip();
+        let forwarded = request.header("X-Forwarded-For").map(|values| values.as_str().to_string()).unwrap_or_default();
+        let mut hops = forwarded.rsplit(',').map(str::trim).filter(|hop| !hop.is_empty());
+        while self.trusted_proxies.contains(&address)
+        {
+            match hops.next().map(|hop| hop.parse::<IpAddr>())
+            {
+                Some(Ok(hop)) => address = hop,
+                // Дальше цепочка неизвестна, клиентом считается последний балансировщик.
+                _ => break,
+            }
+        }
+        Some(address)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for TrustedProxies
+{
+    async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> tide::Result
+    {
+        let address = self.resolve(&request);
+        request.set_ext(ClientAddress(address));
+        Ok(next.run(request).await)
+    }
+}
+
+// Cookie сессии пользователя, если запрос пришел с ней.
+pub fn session_id<State>(request: &Request<State>) -> Option<String>
+{
+    request.cookie(SESSION_COOKIE).map(|cookie| cookie.value().to_string())
+}
+
+pub fn set_session_cookie(response: &mut Response, session_id: &str)
+{
+    response.insert_header("Set-Cookie", format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; SameSite=Lax",
+        SESSION_COOKIE,
+        session_id,
+        Duration::days(SESSION_LIFETIME_DAYS).num_seconds(),
+    ));
+}
+
+pub fn clear_session_cookie(response: &mut Response)
+{
+    response.insert_header("Set-Cookie", format!("{}=; Max-Age=0; Path=/; HttpOnly; SameSite=Lax", SESSION_COOKIE));
+}
+
+fn hash_password(password: &str, salt: &str) -> String
+{
+    let mut hash = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), PASSWORD_HASH_ROUNDS, &mut hash);
+    to_hex(&hash)
+}
+
+fn to_hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn random_hex(len: usize) -> String
+{
+    let mut rng = rand::thread_rng();
+    let bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+    to_hex(&bytes)
+}
+
+pub fn generate_token() -> String
+{
+    random_hex(32)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::net::IpAddr;
+    use tide::http::{Method, Request, Response, Url};
+    use super::{client_address, TrustedProxies};
+
+    // Адрес клиента, который увидит обработчик за TrustedProxies.
+    fn resolve(trusted: &[&str], peer: &str, forwarded: Option<&str>) -> String
+    {
+        let mut app = tide::new();
+        app.with(TrustedProxies::new(trusted.iter().map(|address| address.parse::<IpAddr>().unwrap()).collect()));
+        app.at("/").get(|request: tide::Request<()>| async move { Ok(client_address(&request)) });
+        let mut request = Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+        request.set_peer_addr(Some(peer));
+        if let Some(forwarded) = forwarded
+        {
+            request.insert_header("X-Forwarded-For", forwarded);
+        }
+        let mut response: Response = futures::executor::block_on(app.respond(request)).unwrap();
+        futures::executor::block_on(response.body_string()).unwrap()
+    }
+
+    #[test]
+    fn ignores_forwarded_from_untrusted_peer()
+    {
+        assert_eq!(resolve(&[], "10.0.0.1:5000", Some("1.2.3.4")), "10.0.0.1");
+        assert_eq!(resolve(&["10.0.0.2"], "10.0.0.1:5000", Some("1.2.3.4")), "10.0.0.1");
+    }
+
+    #[test]
+    fn takes_first_untrusted_hop_from_the_right()
+    {
+        assert_eq!(resolve(&["10.0.0.1"], "10.0.0.1:5000", Some("1.2.3.4")), "1.2.3.4");
+        // Левые адреса дописал сам клиент, им верить нельзя.
+        assert_eq!(resolve(&["10.0.0.1", "10.0.0.2"], "10.0.0.1:5000", Some("9.9.9.9, 1.2.3.4, 10.0.0.2")), "1.2.3.4");
+    }
+
+    #[test]
+    fn stops_at_missing_or_broken_hop()
+    {
+        assert_eq!(resolve(&["10.0.0.1"], "10.0.0.1:5000", None), "10.0.0.1");
+        assert_eq!(resolve(&["10.0.0.1"], "10.0.0.1:5000", Some("garbage")), "10.0.0.1");
+    }
+}
@@ -0,0 +1,459 @@
+// Настройки сервера.
+//
+// Значения берутся по порядку, каждое следующее переопределяет предыдущее: значения по умолчанию,
+// TOML файл, переменные окружения, флаги командной строки. Каждая настройка описана одной
+// строкой в SETTINGS: ее ключ в файле, переменная окружения и флаг.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::logging::LogFormat;
+
+const DEFAULT_CONFIG_FILE: &str = "secret-santa.toml";
+const CONFIG_ENV: &str = "SECRET_SANTA_CONFIG";
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend
+{
+    // Данные живут только в памяти процесса.
+    Memory,
+    // Данные сохраняются в JSON файл после каждого изменяющего запроса.
+    File,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig
+{
+    pub backend: Backend,
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig
+{
+    // Запросов в минуту от одного клиента на чтение и на изменение.
+    pub read_per_minute: u32,
+    pub write_per_minute: u32,
+    // Сколько секунд хранится ответ на запрос с заголовком Idempotency-Key.
+    pub idempotency_window_secs: u64,
+    // Сколько секунд при остановке ждать завершения начатых запросов.
+    pub shutdown_timeout_secs: u64,
+    // Сколько секунд дается на обработку запроса вместе с чтением тела.
+    pub request_timeout_secs: u64,
+    // Наибольший размер тела запроса и наибольшая вложенность JSON в нем.
+    pub max_body_bytes: usize,
+    pub max_json_depth: usize,
+    // Свои лимиты размера тела для отдельных путей, задаются только в файле.
+    pub route_body_bytes: BTreeMap<String, usize>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig
+{
+    // Как часто в секундах проверять сроки вступления и запланированные жеребьевки.
+    pub interval_secs: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig
+{
+    // Через сколько дней после даты обмена группа уходит в архив.
+    pub archive_after_days: u32,
+    // Через сколько дней после архивации из группы удаляются участники.
+    pub purge_after_days: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeaturesConfig
+{
+    pub rate_limit: bool,
+    pub idempotency: bool,
+    // Архивация и удаление старых групп, выключено по умолчанию, потому что удаляет данные.
+    pub retention: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config
+{
+    pub bind: String,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    // Токен пользователя-оператора, который создается при старте.
+    pub operator_token: Option<String>,
+    // Адреса балансировщиков, которым можно верить в заголовке X-Forwarded-For.
+    pub trusted_proxies: Vec<IpAddr>,
+    pub storage: StorageConfig,
+    pub limits: LimitsConfig,
+    pub scheduler: SchedulerConfig,
+    pub retention: RetentionConfig,
+    pub features: FeaturesConfig,
+}
+
+impl Default for StorageConfig
+{
+    fn default() -> StorageConfig
+    {
+        StorageConfig { backend: Backend::Memory, path: PathBuf::from("secret-santa.json") }
+    }
+}
+
+impl Default for LimitsConfig
+{
+    fn default() -> LimitsConfig
+    {
+        LimitsConfig {
+            read_per_minute: 600,
+            write_per_minute: 60,
+            idempotency_window_secs: 24 * 60 * 60,
+            shutdown_timeout_secs: 30,
+            request_timeout_secs: 30,
+            max_body_bytes: 64 * 1024,
+            max_json_depth: 32,
+            // Массовые операции принимают большие списки.
+            route_body_bytes: BTreeMap::from([
+                ("/user/create_many".to_string(), 1024 * 1024),
+                ("/organization/user/create_many".to_string(), 1024 * 1024),
+                ("/group/join_many".to_string(), 1024 * 1024),
+            ]),
+        }
+    }
+}
+
+impl Default for SchedulerConfig
+{
+    fn default() -> SchedulerConfig
+    {
+        SchedulerConfig { interval_secs: 10 }
+    }
+}
+
+impl Default for RetentionConfig
+{
+    fn default() -> RetentionConfig
+    {
+        RetentionConfig { archive_after_days: 30, purge_after_days: 365 }
+    }
+}
+
+impl Default for FeaturesConfig
+{
+    fn default() -> FeaturesConfig
+    {
+        FeaturesConfig { rate_limit: true, idempotency: true, retention: false }
+    }
+}
+
+impl Default for Config
+{
+    fn default() -> Config
+    {
+        Config {
+            bind: "127.0.0.1:8080".to_string(),
+            log_level: "info".to_string(),
+            log_format: LogFormat::Text,
+            operator_token: None,
+            trusted_proxies: Vec::new(),
+            storage: StorageConfig::default(),
+            limits: LimitsConfig::default(),
+            scheduler: SchedulerConfig::default(),
+            retention: RetentionConfig::default(),
+            features: FeaturesConfig::default(),
+        }
+    }
+}
+
+// Ключ в файле, переменная окружения и флаг командной строки. У токена оператора нет флага,
+// чтобы он не попадал в список процессов.
+const SETTINGS: &[(&str, &str, Option<&str>)] = &[
+    ("bind", "SECRET_SANTA_BIND", Some("--bind")),
+    ("log_level", "SECRET_SANTA_LOG_LEVEL", Some("--log-level")),
+    ("log_format", "SECRET_SANTA_LOG_FORMAT", Some("--log-format")),
+    ("operator_token", "SECRET_SANTA_OPERATOR_TOKEN", None),
+    ("trusted_proxies", "SECRET_SANTA_TRUSTED_PROXIES", Some("--trusted-proxies")),
+    ("storage.backend", "SECRET_SANTA_STORAGE", Some("--storage")),
+    ("storage.path", "SECRET_SANTA_STORAGE_PATH", Some("--storage-path")),
+    ("limits.read_per_minute", "SECRET_SANTA_READ_RATE_LIMIT", Some("--read-rate-limit")),
+    ("limits.write_per_minute", "SECRET_SANTA_WRITE_RATE_LIMIT", Some("--write-rate-limit")),
+    ("limits.idempotency_window_secs", "SECRET_SANTA_IDEMPOTENCY_WINDOW", Some("--idempotency-window")),
+    ("limits.shutdown_timeout_secs", "SECRET_SANTA_SHUTDOWN_TIMEOUT", Some("--shutdown-timeout")),
+    ("limits.request_timeout_secs", "SECRET_SANTA_REQUEST_TIMEOUT", Some("--request-timeout")),
+    ("limits.max_body_bytes", "SECRET_SANTA_MAX_BODY_BYTES", Some("--max-body-bytes")),
+    ("limits.max_json_depth", "SECRET_SANTA_MAX_JSON_DEPTH", Some("--max-json-depth")),
+    ("scheduler.interval_secs", "SECRET_SANTA_SCHEDULER_INTERVAL", Some("--scheduler-interval")),
+    ("retention.archive_after_days", "SECRET_SANTA_ARCHIVE_AFTER_DAYS", Some("--archive-after-days")),
+    ("retention.purge_after_days", "SECRET_SANTA_PURGE_AFTER_DAYS", Some("--purge-after-days")),
+    ("features.rate_limit", "SECRET_SANTA_RATE_LIMIT", Some("--rate-limit")),
+    ("features.idempotency", "SECRET_SANTA_IDEMPOTENCY", Some("--idempotency")),
+    ("features.retention", "SECRET_SANTA_RETENTION", Some("--retention")),
+];
+
+// Разобранная командная строка.
+pub struct Args
+{
+    // Подкоманда, по умолчанию `serve`, и ее собственные аргументы.
+    pub command: String,
+    pub command_args: Vec<String>,
+    pub config: Option<PathBuf>,
+    pub print_config: bool,
+    // Пары (ключ настройки, значение) из флагов в порядке их появления.
+    overrides: Vec<(&'static str, String)>,
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String>
+{
+    value.parse().map_err(|_| format!("wrong value for {}: {:?}", key, value))
+}
+
+impl Args
+{
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Args, String>
+    {
+        let mut result = Args {
+            command: "serve".to_string(),
+            command_args: Vec::new(),
+            config: None,
+            print_config: false,
+            overrides: Vec::new(),
+        };
+        let mut command = None;
+        let mut args = args.peekable();
+        while let Some(arg) = args.next()
+        {
+            if arg == "--print-config"
+            {
+                result.print_config = true;
+                continue;
+            }
+            // Первый аргумент без `--` - подкоманда, остальные и незнакомые ей флаги разбирает она сама.
+            if !arg.starts_with("--")
+            {
+                match command
+                {
+                    None => command = Some(arg),
+                    Some(_) => result.command_args.push(arg),
+                }
+                continue;
+            }
+            // Поддерживаются обе записи: `--flag value` и `--flag=value`.
+            let (flag, inline_value) = match arg.split_once('=')
+            {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let key = match flag.as_str()
+            {
+                "--config" => None,
+                _ => match SETTINGS.iter().find(|(_, _, setting_flag)| *setting_flag == Some(flag.as_str()))
+                {
+                    Some((key, _, _)) => Some(*key),
+                    None if command.is_some() =>
+                    {
+                        result.command_args.push(arg_text(&flag, &inline_value));
+                        continue;
+                    },
+                    None => return Err(format!("unknown argument {}", flag)),
+                },
+            };
+            let value = match inline_value.or_else(|| args.next())
+            {
+                Some(value) => value,
+                None => return Err(format!("{} needs a value", flag)),
+            };
+            match key
+            {
+                None => result.config = Some(PathBuf::from(value)),
+                Some(key) => result.overrides.push((key, value)),
+            }
+        }
+        if let Some(command) = command
+        {
+            result.command = command;
+        }
+        Ok(result)
+    }
+}
+
+fn arg_text(flag: &str, inline_value: &Option<String>) -> String
+{
+    match inline_value
+    {
+        Some(value) => format!("{}={}", flag, value),
+        None => flag.to_string(),
+    }
+}
+
+impl Config
+{
+    // Собирает настройки из файла, окружения и флагов и проверяет их.
+    pub fn load(args: &Args) -> Result<Config, String>
+    {
+        let path = args.config.clone().or_else(|| std::env::var(CONFIG_ENV).ok().map(PathBuf::from));
+        let mut config = match path
+        {
+            Some(path) => Config::from_file(&path)?,
+            // Файл по умолчанию необязателен.
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Config::default(),
+        };
+        for (key, env, _) in SETTINGS
+        {
+            if let Ok(value) = std::env::var(env)
+            {
+                config.set(key, &value).map_err(|msg| format!("{} (from {})", msg, env))?;
+            }
+        }
+        for (key, value) in &args.overrides
+        {
+            config.set(key, value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, String>
+    {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read config file {}: {}", path.display(), err))?;
+        toml::from_str(&text).map_err(|err| format!("failed to parse config file {}: {}", path.display(), err))
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String>
+    {
+        match key
+        {
+            "bind" => self.bind = value.to_string(),
+            "log_level" => self.log_level = value.to_string(),
+            "log_format" => self.log_format = match value
+            {
+                "text" => LogFormat::Text,
+                "json" => LogFormat::Json,
+                _ => return Err(format!("wrong value for {}: {:?}, expected text or json", key, value)),
+            },
+            "operator_token" => self.operator_token = Some(value.to_string()),
+            // Список через запятую, пустая строка очищает его.
+            "trusted_proxies" => self.trusted_proxies = value.split(',')
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+                .map(|address| parse_value(key, address))
+                .collect::<Result<_, _>>()?,
+            "storage.backend" => self.storage.backend = match value
+            {
+                "memory" => Backend::Memory,
+                "file" => Backend::File,
+                _ => return Err(format!("wrong value for {}: {:?}, expected memory or file", key, value)),
+            },
+            "storage.path" => self.storage.path = PathBuf::from(value),
+            "limits.read_per_minute" => self.limits.read_per_minute = parse_value(key, value)?,
+            "limits.write_per_minute" => self.limits.write_per_minute = parse_value(key, value)?,
+            "limits.idempotency_window_secs" => self.limits.idempotency_window_secs = parse_value(key, value)?,
+            "limits.shutdown_timeout_secs" => self.limits.shutdown_timeout_secs = parse_value(key, value)?,
+            "limits.request_timeout_secs" => self.limits.request_timeout_secs = parse_value(key, value)?,
+            "limits.max_body_bytes" => self.limits.max_body_bytes = parse_value(key, value)?,
+            "limits.max_json_depth" => self.limits.max_json_depth = parse_value(key, value)?,
+            "scheduler.interval_secs" => self.scheduler.interval_secs = parse_value(key, value)?,
+            "retention.archive_after_days" => self.retention.archive_after_days = parse_value(key, value)?,
+            "retention.purge_after_days" => self.retention.purge_after_days = parse_value(key, value)?,
+            "features.rate_limit" => self.features.rate_limit = parse_value(key, value)?,
+            "features.idempotency" => self.features.idempotency = parse_value(key, value)?,
+            "features.retention" => self.features.retention = parse_value(key, value)?,
+            _ => unreachable!("setting {} is not handled", key),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String>
+    {
+        if self.bind.parse::<std::net::SocketAddr>().is_err()
+        {
+            return Err(format!("bind must be an address like 127.0.0.1:8080, got {:?}", self.bind));
+        }
+        if self.log_level.parse::<log::LevelFilter>().is_err()
+        {
+            return Err(format!("log_level must be one of off, error, warn, info, debug, trace, got {:?}", self.log_level));
+        }
+        if matches!(&self.operator_token, Some(token) if token.is_empty())
+        {
+            return Err("operator_token must not be empty".to_string());
+        }
+        if self.storage.backend == Backend::File && self.storage.path.as_os_str().is_empty()
+        {
+            return Err("storage.path must be set for the file backend".to_string());
+        }
+        if self.limits.read_per_minute == 0 || self.limits.write_per_minute == 0
+        {
+            return Err("rate limits must be positive, disable features.rate_limit instead".to_string());
+        }
+        if self.limits.idempotency_window_secs == 0
+        {
+            return Err("limits.idempotency_window_secs must be positive".to_string());
+        }
+        if self.limits.request_timeout_secs == 0 || self.limits.max_body_bytes == 0 || self.limits.max_json_depth == 0
+        {
+            return Err("limits.request_timeout_secs, limits.max_body_bytes and limits.max_json_depth must be positive".to_string());
+        }
+        if let Some((path, _)) = self.limits.route_body_bytes.iter().find(|(path, bytes)| !path.starts_with('/') || **bytes == 0)
+        {
+            return Err(format!("limits.route_body_bytes needs paths starting with / and positive sizes, got {:?}", path));
+        }
+        if self.scheduler.interval_secs == 0
+        {
+            return Err("scheduler.interval_secs must be positive".to_string());
+        }
+        if self.retention.archive_after_days == 0 || self.retention.purge_after_days == 0
+        {
+            return Err("retention periods must be positive, disable features.retention instead".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> log::LevelFilter
+    {
+        self.log_level.parse().unwrap()
+    }
+
+    pub fn idempotency_window(&self) -> Duration
+    {
+        Duration::from_secs(self.limits.idempotency_window_secs)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration
+    {
+        Duration::from_secs(self.limits.shutdown_timeout_secs)
+    }
+
+    pub fn request_timeout(&self) -> Duration
+    {
+        Duration::from_secs(self.limits.request_timeout_secs)
+    }
+
+    pub fn scheduler_interval(&self) -> Duration
+    {
+        Duration::from_secs(self.scheduler.interval_secs)
+    }
+
+    // Сроки хранения групп, если архивация включена.
+    pub fn retention(&self) -> Option<RetentionConfig>
+    {
+        self.features.retention.then(|| self.retention.clone())
+    }
+
+    // Итоговые настройки в формате TOML, токен оператора скрыт.
+    pub fn to_toml(&self) -> String
+    {
+        let mut config = self.clone();
+        if config.operator_token.is_some()
+        {
+            config.operator_token = Some("<hidden>".to_string());
+        }
+        toml::to_string(&config).unwrap()
+    }
+}
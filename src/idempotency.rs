@@ -0,0 +1,248 @@
+// Повтор ответов на изменяющие запросы с заголовком Idempotency-Key.
+//
+// Первый ответ на POST/PUT/DELETE с ключом запоминается на время окна и возвращается
+// без повторного выполнения на каждый запрос того же клиента с тем же ключом, методом и путем.
+// Клиент определяется по заголовку `Authorization`, cookie сессии или, без них, по IP адресу,
+// чтобы один клиент не мог получить сохраненный ответ другого.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tide::http::{Method, Mime};
+use tide::{Body, Middleware, Next, Request, Response};
+
+const HEADER: &str = "Idempotency-Key";
+const MAX_KEY_LEN: usize = 255;
+// Заголовки ответа, которые повторяются вместе с телом.
+const REPLAYED_HEADERS: [&str; 2] = ["ETag", "Set-Cookie"];
+
+struct StoredResponse
+{
+    status: u16,
+    body: Vec<u8>,
+    mime: Option<Mime>,
+    headers: Vec<(&'static str, String)>,
+}
+
+struct Entry
+{
+    created: Instant,
+    // Хэш тела запроса, чтобы не отдавать ответ на другой запрос с тем же ключом.
+    fingerprint: u64,
+    // None, пока первый запрос еще выполняется.
+    response: Option<StoredResponse>,
+}
+
+// Запись запроса, который еще выполняется. Если ответ так и не запомнен - обработчик упал,
+// запрос прерван или ответ с ошибкой сервера, - запись удаляется, и запрос можно повторить.
+struct Pending<'a>
+{
+    entries: &'a Mutex<HashMap<String, Entry>>,
+    key: String,
+    stored: bool,
+}
+
+impl Drop for Pending<'_>
+{
+    fn drop(&mut self)
+    {
+        if !self.stored
+        {
+            if let Ok(mut entries) = self.entries.lock()
+            {
+                entries.remove(&self.key);
+            }
+        }
+    }
+}
+
+pub struct Idempotency
+{
+    window: Duration,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl Idempotency
+{
+    pub fn new(window: Duration) -> Idempotency
+    {
+        Idempotency {
+            window,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+fn body_with_mime(bytes: Vec<u8>, mime: Option<Mime>) -> Body
+{
+    let mut body = Body::from_bytes(bytes);
+    if let Some(mime) = mime
+    {
+        body.set_mime(mime);
+    }
+    body
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Idempotency
+{
+    async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> tide::Result
+    {
+        if !matches!(request.method(), Method::Post | Method::Put | Method::Delete)
+        {
+            return Ok(next.run(request).await);
+        }
+        let key = match request.header(HEADER)
+        {
+            None => return Ok(next.run(request).await),
+            Some(values) => values.as_str().to_string(),
+        };
+        if key.is_empty() || key.len() > MAX_KEY_LEN
+        {
+            return Ok(crate::response_error("wrong format Idempotency-Key"));
+        }
+        let caller = match (request.header("Authorization"), crate::auth::session_id(&request))
+        {
+            (Some(authorization), _) => format!("authorization {}", authorization.as_str()),
+            (None, Some(session)) => format!("session {}", session),
+            // Повтор может прийти по другому соединению, поэтому по адресу без порта.
+            (None, None) => format!("address {}", crate::auth::client_address(&request)),
+        };
+        let key = format!("{} {} {} {}", caller, request.method(), request.url().path(), key);
+
+        let mime = request.content_type();
+        let body = request.take_body().into_bytes().await?;
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        let fingerprint = hasher.finish();
+
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|_, entry| entry.created.elapsed() < self.window);
+            match entries.get(&key)
+            {
+                Some(entry) if entry.fingerprint != fingerprint =>
+                {
+                    return Ok(crate::response_error_with_status(422, "Idempotency-Key was used with another request"));
+                },
+                Some(Entry { response: None, .. }) =>
+                {
+                    return Ok(crate::response_error_with_status(409, "request with this Idempotency-Key is in progress"));
+                },
+                Some(Entry { response: Some(stored), .. }) =>
+                {
+                    let mut response = Response::new(stored.status);
+                    response.set_body(body_with_mime(stored.body.clone(), stored.mime.clone()));
+                    for (name, value) in &stored.headers
+                    {
+                        response.append_header(*name, value.as_str());
+                    }
+                    response.insert_header("Idempotent-Replayed", "true");
+                    return Ok(response);
+                },
+                None =>
+                {
+                    entries.insert(key.clone(), Entry { created: Instant::now(), fingerprint, response: None });
+                },
+            }
+        }
+        let mut pending = Pending { entries: &self.entries, key, stored: false };
+
+        request.set_body(body_with_mime(body, mime));
+        let mut response = next.run(request).await;
+
+        // Ответы с ошибкой сервера не запоминаются, чтобы запрос можно было повторить.
+        if response.status().is_server_error()
+        {
+            return Ok(response);
+        }
+        let mime = response.content_type();
+        let bytes = response.take_body().into_bytes().await?;
+        response.set_body(body_with_mime(bytes.clone(), mime.clone()));
+        let headers = REPLAYED_HEADERS.iter()
+            .flat_map(|&name| response.header(name).into_iter().flatten().map(move |value| (name, value.as_str().to_string())))
+            .collect();
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&pending.key)
+        {
+            entry.response = Some(StoredResponse { status: response.status().into(), body: bytes, mime, headers });
+            pending.stored = true;
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::panic::AssertUnwindSafe;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use futures::FutureExt;
+    use tide::http::{Method, Request, Response, Url};
+    use super::Idempotency;
+
+    // Сервер, у которого первый вызов обработчика ведет себя как first, а следующие отвечают 200.
+    fn server(first: &'static str) -> tide::Server<Arc<AtomicU32>>
+    {
+        let mut app = tide::with_state(Arc::new(AtomicU32::new(0)));
+        app.with(Idempotency::new(Duration::from_secs(60)));
+        app.at("/action").post(move |request: tide::Request<Arc<AtomicU32>>| async move {
+            if request.state().fetch_add(1, Ordering::SeqCst) == 0
+            {
+                match first
+                {
+                    "panic" => panic!("handler failed"),
+                    "hang" => futures::future::pending::<()>().await,
+                    _ => return Ok(tide::Response::new(500)),
+                }
+            }
+            Ok(tide::Response::new(200))
+        });
+        app
+    }
+
+    fn request() -> Request
+    {
+        let mut request = Request::new(Method::Post, Url::parse("http://localhost/action").unwrap());
+        request.insert_header("Idempotency-Key", "k1");
+        request.set_body("{}");
+        request
+    }
+
+    fn send(app: &tide::Server<Arc<AtomicU32>>) -> Response
+    {
+        futures::executor::block_on(app.respond::<Request, Response>(request())).unwrap()
+    }
+
+    #[test]
+    fn key_is_retryable_after_panic()
+    {
+        let app = server("panic");
+        let first = futures::executor::block_on(AssertUnwindSafe(app.respond::<Request, Response>(request())).catch_unwind());
+        assert!(first.is_err());
+        assert_eq!(send(&app).status(), 200);
+    }
+
+    #[test]
+    fn key_is_retryable_after_cancel()
+    {
+        let app = server("hang");
+        assert!(app.respond::<Request, Response>(request()).now_or_never().is_none());
+        assert_eq!(send(&app).status(), 200);
+    }
+
+    #[test]
+    fn key_is_retryable_after_server_error()
+    {
+        let app = server("error");
+        assert_eq!(send(&app).status(), 500);
+        assert_eq!(send(&app).status(), 200);
+        // Успешный ответ уже запоминается.
+        let replayed = send(&app);
+        assert_eq!(replayed.status(), 200);
+        assert!(replayed.header("Idempotent-Replayed").is_some());
+    }
+}
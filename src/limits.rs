@@ -0,0 +1,168 @@
+// Защита от запросов, которые занимают сервер слишком надолго.
+//
+// Timeout отвечает `408`, если запрос не обработан за заданное время, например клиент слишком
+// медленно отправляет тело. BodyLimit читает тело не больше лимита маршрута и отвечает `413`,
+// если оно больше, а `400`, если JSON в нем вложен глубже допустимого: serde_json разбирает
+// вложенность рекурсивно.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use futures::AsyncReadExt;
+use tide::{Middleware, Next, Request};
+
+pub struct Timeout
+{
+    timeout: Duration,
+}
+
+impl Timeout
+{
+    pub fn new(timeout: Duration) -> Timeout
+    {
+        Timeout { timeout }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Timeout
+{
+    async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> tide::Result
+    {
+        // Обработчики меняют базу без ожиданий внутри, поэтому прерванный запрос не оставляет ее наполовину измененной.
+        match async_std::future::timeout(self.timeout, next.run(request)).await
+        {
+            Ok(response) => Ok(response),
+            Err(_) =>
+            {
+                let mut response = crate::response_error_with_status(408, "request timeout");
+                response.insert_header("Connection", "close");
+                Ok(response)
+            },
+        }
+    }
+}
+
+pub struct BodyLimit
+{
+    max_bytes: usize,
+    // Путь маршрута -> свой лимит вместо max_bytes.
+    route_bytes: BTreeMap<String, usize>,
+    max_json_depth: usize,
+}
+
+impl BodyLimit
+{
+    pub fn new(max_bytes: usize, route_bytes: BTreeMap<String, usize>, max_json_depth: usize) -> BodyLimit
+    {
+        BodyLimit { max_bytes, route_bytes, max_json_depth }
+    }
+}
+
+// Наибольшая вложенность массивов и объектов JSON. Скобки внутри строк не считаются.
+fn json_depth(bytes: &[u8]) -> usize
+{
+    let mut depth = 0usize;
+    let mut max_depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in bytes
+    {
+        if in_string
+        {
+            match byte
+            {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+        match byte
+        {
+            b'"' => in_string = true,
+            b'[' | b'{' =>
+            {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            },
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+    max_depth
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for BodyLimit
+{
+    async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> tide::Result
+    {
+        let limit = self.route_bytes.get(request.url().path()).copied().unwrap_or(self.max_bytes);
+        let too_large = || crate::response_error_with_status(413, &format!("request body is larger than {} bytes", limit));
+        // По заявленной длине можно отказать, не читая тело.
+        if request.len().is_some_and(|len| len > limit)
+        {
+            return Ok(too_large());
+        }
+        // set_body заменяет Content-Type на тип тела, исходный возвращается на место.
+        let content_type = request.header("Content-Type").map(|values| values.as_str().to_string());
+        let mut bytes = Vec::new();
+        let read = request.take_body().take(limit as u64 + 1).read_to_end(&mut bytes).await;
+        if read.is_err()
+        {
+            return Ok(crate::response_error("failed to read request body"));
+        }
+        if bytes.len() > limit
+        {
+            return Ok(too_large());
+        }
+        if json_depth(&bytes) > self.max_json_depth
+        {
+            return Ok(crate::response_error("json is nested too deeply"));
+        }
+        request.set_body(bytes);
+        if let Some(content_type) = content_type
+        {
+            request.insert_header("Content-Type", content_type.as_str());
+        }
+        Ok(next.run(request).await)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::json_depth;
+
+    #[test]
+    fn counts_nesting()
+    {
+        assert_eq!(json_depth(b"42"), 0);
+        assert_eq!(json_depth(b"{}"), 1);
+        assert_eq!(json_depth(br#"{"a": [1, {"b": []}], "c": {}}"#), 4);
+        assert_eq!(json_depth(b"[[[]], []]"), 3);
+    }
+
+    #[test]
+    fn ignores_brackets_in_strings()
+    {
+        assert_eq!(json_depth(br#"{"name": "[[[{{{"}"#), 1);
+        assert_eq!(json_depth(br#"["]]]", "}"]"#), 1);
+    }
+
+    #[test]
+    fn handles_escaped_quotes()
+    {
+        // Экранированная кавычка не закрывает строку, скобки после нее все еще внутри строки.
+        assert_eq!(json_depth(br#"{"a": "x\"[[[["}"#), 1);
+        // Экранированная обратная косая черта перед кавычкой строку закрывает.
+        assert_eq!(json_depth(br#"{"a": "x\\", "b": [[]]}"#), 3);
+    }
+
+    #[test]
+    fn stops_counting_on_extra_closing()
+    {
+        assert_eq!(json_depth(b"]]][[]"), 2);
+    }
+}
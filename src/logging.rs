@@ -0,0 +1,241 @@
+// Журнал сервера и запись каждого запроса в него.
+//
+// Строки пишутся в stderr в текстовом виде или по одному JSON объекту на строку. Каждому запросу
+// выдается ID: он берется из заголовка `X-Request-Id` или генерируется, возвращается в том же
+// заголовке ответа и в поле `request_id` тела ошибок, чтобы по жалобе можно было найти запрос.
+
+use std::io::Write;
+use std::panic::AssertUnwindSafe;
+use std::time::Instant;
+use chrono::{SecondsFormat, Utc};
+use futures::FutureExt;
+use log::kv::{Key, Value, VisitSource, VisitValue};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map};
+use tide::{Middleware, Next, Request, Response};
+use crate::auth::AuthUser;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat
+{
+    Text,
+    Json,
+}
+
+struct Logger
+{
+    format: LogFormat,
+}
+
+// Пары ключ-значение записи в том порядке, в котором они переданы.
+struct Fields(Vec<(String, serde_json::Value)>);
+
+// Значение из пары в виде JSON значения.
+struct ToJson(serde_json::Value);
+
+impl<'v> VisitValue<'v> for &mut ToJson
+{
+    fn visit_any(&mut self, value: Value) -> Result<(), log::kv::Error>
+    {
+        self.0 = json!(value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), log::kv::Error>
+    {
+        self.0 = serde_json::Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), log::kv::Error>
+    {
+        self.0 = json!(value);
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), log::kv::Error>
+    {
+        self.0 = json!(value);
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), log::kv::Error>
+    {
+        self.0 = json!(value);
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), log::kv::Error>
+    {
+        self.0 = json!(value);
+        Ok(())
+    }
+}
+
+impl<'kvs> VisitSource<'kvs> for Fields
+{
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error>
+    {
+        let mut json = ToJson(serde_json::Value::Null);
+        value.visit(&mut json)?;
+        self.0.push((key.to_string(), json.0));
+        Ok(())
+    }
+}
+
+impl Log for Logger
+{
+    fn enabled(&self, metadata: &Metadata) -> bool
+    {
+        // Вместо встроенной в tide записи запросов используется RequestLog.
+        metadata.level() <= log::max_level() && !metadata.target().starts_with("tide::log::middleware")
+    }
+
+    fn log(&self, record: &Record)
+    {
+        if !self.enabled(record.metadata())
+        {
+            return;
+        }
+        let mut fields = Fields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let line = match self.format
+        {
+            LogFormat::Text =>
+            {
+                let mut line = format!("{} {:<5} {} {}", time, record.level(), record.target(), record.args());
+                for (key, value) in fields.0
+                {
+                    match value
+                    {
+                        // Кавычки только там, где без них строку не разобрать.
+                        serde_json::Value::String(value) if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"') =>
+                        {
+                            line.push_str(&format!(" {}={:?}", key, value))
+                        },
+                        serde_json::Value::String(value) => line.push_str(&format!(" {}={}", key, value)),
+                        serde_json::Value::Null => line.push_str(&format!(" {}=-", key)),
+                        value => line.push_str(&format!(" {}={}", key, value)),
+                    }
+                }
+                line
+            },
+            LogFormat::Json =>
+            {
+                let mut object: Map<String, serde_json::Value> = fields.0.into_iter().collect();
+                object.insert("time".to_string(), json!(time));
+                object.insert("level".to_string(), json!(record.level().as_str()));
+                object.insert("target".to_string(), json!(record.target()));
+                object.insert("message".to_string(), json!(record.args().to_string()));
+                serde_json::Value::Object(object).to_string()
+            },
+        };
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self)
+    {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+pub fn init(level: LevelFilter, format: LogFormat)
+{
+    log::set_boxed_logger(Box::new(Logger { format })).expect("logger is already set");
+    log::set_max_level(level);
+}
+
+fn request_id<State>(request: &Request<State>) -> String
+{
+    let incoming = request.header(REQUEST_ID_HEADER).map(|values| values.as_str().to_string());
+    match incoming
+    {
+        Some(id) if !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LEN
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') => id,
+        _ => crate::auth::random_hex(8),
+    }
+}
+
+// Добавляет request_id в JSON тело ошибки. Ошибка, которую обработчик вернул через `?`, например
+// из body_json, приходит без тела и сначала получает тело вида {"error": ...}.
+async fn add_request_id_to_error(response: &mut Response, id: &str) -> tide::Result<()>
+{
+    if let Some(error) = response.error()
+    {
+        // Подробности внутренних ошибок остаются в журнале.
+        let msg = match response.status().is_server_error()
+        {
+            true => "internal server error".to_string(),
+            false => error.to_string(),
+        };
+        response.set_body(tide::Body::from_json(&json!({"error": msg}))?);
+    }
+    let is_json = response.content_type().is_some_and(|mime| mime.essence() == "application/json");
+    if !response.status().is_success() && is_json
+    {
+        let mut body: serde_json::Value = response.take_body().into_json().await?;
+        if let Some(object) = body.as_object_mut()
+        {
+            object.insert("request_id".to_string(), json!(id));
+        }
+        response.set_body(tide::Body::from_json(&body)?);
+    }
+    Ok(())
+}
+
+pub struct RequestLog;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequestLog
+{
+    async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> tide::Result
+    {
+        let started = Instant::now();
+        let id = request_id(&request);
+        let method = request.method().to_string();
+        let path = request.url().path().to_string();
+
+        // Паника в обработчике иначе просто обрывает соединение, не оставляя следа в журнале.
+        let mut response = match AssertUnwindSafe(next.run(request)).catch_unwind().await
+        {
+            Ok(response) => response,
+            Err(panic) =>
+            {
+                let msg = panic.downcast_ref::<&str>().map(|msg| msg.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                log::error!(target: "request", request_id = id.as_str(), panic = msg.as_str(); "handler panicked");
+                crate::response_error_with_status(500, "internal server error")
+            },
+        };
+        add_request_id_to_error(&mut response, &id).await?;
+        response.insert_header(REQUEST_ID_HEADER, id.as_str());
+
+        let status: u16 = response.status().into();
+        let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+        // ID пользователя кладет в ответ middleware аутентификации.
+        let user_id = response.ext::<AuthUser>().map(|user| user.0);
+        let error = response.error().map(|err| err.to_string());
+        let level = if status >= 500 { log::Level::Error } else { log::Level::Info };
+        log::log!(
+            target: "request",
+            level,
+            request_id = id.as_str(),
+            method = method.as_str(),
+            path = path.as_str(),
+            status = status,
+            latency_ms = (latency_ms * 1000.0).round() / 1000.0,
+            user_id = user_id,
+            error = error.as_deref();
+            "completed"
+        );
+        Ok(response)
+    }
+}
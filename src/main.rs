@@ -1,47 +1,294 @@
 // # Веб-сервис секретного Санты.
 
-use std::collections::HashMap;
+mod auth;
+mod config;
+mod idempotency;
+mod limits;
+mod logging;
+mod manage;
+mod metrics;
+mod rate_limit;
+mod scheduler;
+mod shutdown;
+mod storage;
+
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
 use std::sync::{Arc, Mutex};
 use tide::{Request, Response};
 use serde_json::{Value, json, Map};
+use chrono::{DateTime, NaiveDate, Utc};
+use sha2::{Digest, Sha256};
 
-#[derive(PartialEq,Eq, Clone)]
+#[derive(PartialEq,Eq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 enum Access
 {
     User,
     Admin,
+    // Создатель группы. У владельца все права, он один в группе.
+    Owner,
+}
+impl Access {
+    fn is_admin(&self) -> bool {
+        matches!(self, Access::Admin | Access::Owner)
+    }
+}
+
+// Действия в группе, которые владелец может разрешить администраторам и обычным участникам.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Permission
+{
+    Invite,
+    Approve,
+    Kick,
+    Draw,
+    Delete,
+    EditMetadata,
+}
+impl Permission {
+    fn name(&self) -> &'static str {
+        match self {
+            Permission::Invite => "invite",
+            Permission::Approve => "approve",
+            Permission::Kick => "kick",
+            Permission::Draw => "draw",
+            Permission::Delete => "delete",
+            Permission::EditMetadata => "edit_metadata",
+        }
+    }
 }
 
 type Id = u32;
 
-#[derive(Eq, Hash, PartialEq, Clone, serde::Serialize)]
+// Номер версии записи, увеличивается при каждом ее изменении. Отдается клиенту как ETag.
+type Revision = u64;
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct User
+{
+    name: String,
+    revision: Revision,
+    // Логин и пароль для входа через сессию. None, если пользователь входит только по токену.
+    credentials: Option<auth::Credentials>,
+    // Оператор сервера может чинить группы и заводить организации через /operator/*.
+    is_operator: bool,
+    organization_id: Id,
+    // Администратор организации заводит в ней пользователей через /organization/*.
+    is_org_admin: bool,
+}
+
+// Организация отделяет своих пользователей и группы от остальных: списки и карточки показывают
+// только записи организации текущего пользователя, а вступить можно только в группы своей организации.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct Organization
+{
+    name: String,
+    revision: Revision,
+}
+
+// Организация, в которую попадают пользователи, зарегистрировавшиеся через /user/create.
+const DEFAULT_ORGANIZATION: Id = 0;
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct Group
+{
+    is_closed: bool,
+    revision: Revision,
+    budget: Option<u32>,
+    exchange_date: Option<NaiveDate>,
+    // После этого момента планировщик закрывает группу, вступить в нее уже нельзя.
+    join_deadline: Option<DateTime<Utc>>,
+    // В этот момент планировщик сам проводит жеребьевку.
+    draw_at: Option<DateTime<Utc>>,
+    // Почему не удалась запланированная жеребьевка.
+    scheduled_draw_error: Option<String>,
+    // Архивная группа доступна только для чтения и не видна в списке групп по умолчанию.
+    archived_at: Option<DateTime<Utc>>,
+    // Когда из архивной группы удалены участники. От нее остается только обезличенная статистика.
+    purged_at: Option<DateTime<Utc>>,
+    stats: Option<GroupStats>,
+    // Вступление через /group/join только после одобрения администратором.
+    approval_required: bool,
+    admin_permissions: HashSet<Permission>,
+    user_permissions: HashSet<Permission>,
+    // Обязательство, опубликованное при жеребьевке.
+    commitment: Option<Commitment>,
+    organization_id: Id,
+}
+impl Group {
+    fn new(organization_id: Id) -> Group {
+        Group {
+            is_closed: false,
+            revision: 1,
+            budget: None,
+            exchange_date: None,
+            join_deadline: None,
+            draw_at: None,
+            scheduled_draw_error: None,
+            archived_at: None,
+            purged_at: None,
+            stats: None,
+            approval_required: false,
+            // Удалить группу по умолчанию может только владелец.
+            admin_permissions: HashSet::from([
+                Permission::Invite,
+                Permission::Approve,
+                Permission::Kick,
+                Permission::Draw,
+                Permission::EditMetadata,
+            ]),
+            user_permissions: HashSet::new(),
+            commitment: None,
+            organization_id,
+        }
+    }
+}
+
+// Что остается от группы после удаления участников.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct GroupStats
+{
+    members: usize,
+    // Сколько участников заполнили список желаний.
+    wishlists: usize,
+    was_drawn: bool,
+}
+
+// Хэш пар жеребьевки с секретной солью. Хэш публикуется сразу, соль - при раскрытии пар,
+// чтобы участники могли проверить, что пары не подменили после жеребьевки.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct Commitment
+{
+    hash: String,
+    salt: String,
+}
+
+impl Commitment
+{
+    fn new(assignments: &[(Id, Id)]) -> Commitment
+    {
+        let salt = auth::random_hex(16);
+        let hash = Commitment::hash(&salt, assignments);
+        Commitment { hash, salt }
+    }
+
+    // SHA-256 от строки `<salt>|<santa_id>:<recipient_id>,...` с парами по возрастанию santa_id.
+    fn hash(salt: &str, assignments: &[(Id, Id)]) -> String
+    {
+        let pairs: Vec<String> = assignments.iter()
+            .map(|(santa_id, recipient_id)| format!("{}:{}", santa_id, recipient_id))
+            .collect();
+        format!("{:x}", Sha256::digest(format!("{}|{}", salt, pairs.join(","))))
+    }
+}
+
+#[derive(Eq, Hash, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
 struct UserGroupId
 {
     user_id: Id,
     group_id: Id,
 }
-#[derive(Clone)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 struct UserGroupProps
 {
     access_level: Access,
-    santa_id: Id,
+    // ID пользователя, для которого этот участник стал тайным Сантой. None до жеребьевки.
+    santa_id: Option<Id>,
+    wishlist: String,
+    revision: Revision,
 }
 impl UserGroupProps {
     fn new(access_level: Access) -> UserGroupProps {
         UserGroupProps {
             access_level,
-            santa_id: 0,
+            santa_id: None,
+            wishlist: String::new(),
+            revision: 1,
+        }
+    }
+}
+
+// Приглашение в группу по коду. Вступление по коду проходит те же проверки, что и /group/join.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct Invite
+{
+    group_id: Id,
+    created_by: Id,
+    expires_at: Option<DateTime<Utc>>,
+    max_uses: Option<u32>,
+    uses: u32,
+}
+impl Invite {
+    fn check(&self) -> Result<(), &'static str> {
+        if matches!(self.expires_at, Some(expires_at) if expires_at <= Utc::now())
+        {
+            Err("invite expired")
+        }
+        else if matches!(self.max_uses, Some(max_uses) if self.uses >= max_uses)
+        {
+            Err("invite is used up")
+        }
+        else
+        {
+            Ok(())
         }
     }
 }
 
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 struct DataBase
 {
-    users: HashMap<Id, String>,
+    users: HashMap<Id, User>,
     users_max_id: Id,
-    groups: HashMap<Id, bool>,
+    groups: HashMap<Id, Group>,
     groups_max_id: Id,
+    #[serde(with = "storage::as_pairs")]
     user_groups: HashMap<UserGroupId, UserGroupProps>,
+    // Токен доступа -> ID пользователя.
+    tokens: HashMap<String, Id>,
+    // ID сессии из cookie -> сессия.
+    sessions: HashMap<String, auth::Session>,
+    // Код приглашения -> приглашение.
+    invites: HashMap<String, Invite>,
+    // Заявки на вступление в группы, ждущие одобрения, и время их создания.
+    #[serde(with = "storage::as_pairs")]
+    join_requests: HashMap<UserGroupId, DateTime<Utc>>,
+    // Пользователи, исключенные из групп без права вернуться.
+    bans: HashSet<UserGroupId>,
+    organizations: HashMap<Id, Organization>,
+    organizations_max_id: Id,
+    // Оператор, которому принадлежит operator_token из настроек, и этот токен. Операторы из
+    // команды create-operator сюда не попадают, их токены смена operator_token не трогает.
+    configured_operator: Option<ConfiguredOperator>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct ConfiguredOperator
+{
+    user_id: Id,
+    token: String,
+}
+
+impl DataBase {
+    fn new() -> DataBase {
+        DataBase {
+            users: HashMap::new(),
+            users_max_id: 0,
+            groups: HashMap::new(),
+            groups_max_id: 0,
+            user_groups: HashMap::new(),
+            tokens: HashMap::new(),
+            sessions: HashMap::new(),
+            invites: HashMap::new(),
+            join_requests: HashMap::new(),
+            bans: HashSet::new(),
+            organizations: HashMap::from([(DEFAULT_ORGANIZATION, Organization { name: "default".to_string(), revision: 1 })]),
+            organizations_max_id: DEFAULT_ORGANIZATION + 1,
+            configured_operator: None,
+        }
+    }
 }
 
 fn get_field<T>(object: &serde_json::Map<String, Value>, key: &str) -> T
@@ -66,238 +313,1874 @@ fn response_empty() -> Response
 
 fn response_error(msg: &str) -> Response
 {
-    Response::builder(400)
+    response_error_with_status(400, msg)
+}
+
+fn response_error_with_status(status: u16, msg: &str) -> Response
+{
+    Response::builder(status)
         .body(tide::Body::from_json(&json!({"error": msg})).unwrap())
         .build()
 }
 
+fn etag(revision: Revision) -> String
+{
+    format!("\"{}\"", revision)
+}
+
+fn with_etag(mut response: Response, revision: Revision) -> Response
+{
+    response.insert_header("ETag", etag(revision));
+    response
+}
+
+// Значение заголовка If-Match. Читается до блокировки состояния.
+fn if_match<State>(request: &Request<State>) -> Option<String>
+{
+    request.header("If-Match").map(|values| values.as_str().to_string())
+}
+
+// Без заголовка If-Match изменение разрешено всегда.
+fn revision_matches(if_match: &Option<String>, revision: Revision) -> bool
+{
+    match if_match
+    {
+        None => true,
+        Some(value) => value.split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag(revision)),
+    }
+}
+
+fn response_precondition_failed(revision: Revision) -> Response
+{
+    with_etag(response_error_with_status(412, "revision mismatch"), revision)
+}
+
+// Параметры запроса списков: курсор, размер страницы, фильтры и сортировка.
+#[derive(serde::Deserialize)]
+struct ListQuery
+{
+    cursor: Option<String>,
+    limit: Option<usize>,
+    sort: Option<String>,
+    order: Option<String>,
+    name: Option<String>,
+    state: Option<String>,
+}
+
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const MAX_PAGE_LIMIT: usize = 500;
+
+// Ключ сортировки элемента списка: значение поля сортировки и ID, чтобы порядок был однозначным.
+// При сортировке по ID строковая часть пустая.
+type SortKey = (String, Id);
+
+fn encode_cursor(key: &SortKey) -> String
+{
+    format!("{}:{}", key.1, key.0)
+}
+
+fn decode_cursor(cursor: &str) -> Option<SortKey>
+{
+    let (id, value) = cursor.split_once(':')?;
+    Some((value.to_string(), id.parse().ok()?))
+}
+
+// Возвращает страницу элементов после курсора в виде {"items": [...], "next_cursor": ...}.
+fn paginate(mut items: Vec<(SortKey, Value)>, query: &ListQuery) -> Result<Value, &'static str>
+{
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if limit == 0 || limit > MAX_PAGE_LIMIT
+    {
+        return Err("wrong limit");
+    }
+    let descending = match query.order.as_deref()
+    {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(_) => return Err("wrong order"),
+    };
+    let cursor = match &query.cursor
+    {
+        None => None,
+        Some(raw) => Some(decode_cursor(raw).ok_or("wrong format cursor")?),
+    };
+
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+    if descending
+    {
+        items.reverse();
+    }
+    if let Some(cursor) = cursor
+    {
+        items.retain(|item| if descending { item.0 < cursor } else { item.0 > cursor });
+    }
+
+    let next_cursor = if items.len() > limit { Some(encode_cursor(&items[limit - 1].0)) } else { None };
+    items.truncate(limit);
+    let items: Vec<Value> = items.into_iter().map(|item| item.1).collect();
+    Ok(json!({"items": items, "next_cursor": next_cursor}))
+}
+
+fn list_users(users: &HashMap<Id, User>, organization_id: Id, query: &ListQuery) -> Result<Value, &'static str>
+{
+    let by_name = match query.sort.as_deref()
+    {
+        None | Some("id") => false,
+        Some("name") => true,
+        Some(_) => return Err("wrong sort field"),
+    };
+    let pattern = query.name.as_ref().map(|name| name.to_lowercase());
+    let items = users.iter()
+        .filter(|&(_, user)| user.organization_id == organization_id)
+        .filter(|&(_, user)| match &pattern
+        {
+            None => true,
+            Some(pattern) => user.name.to_lowercase().contains(pattern),
+        })
+        .map(|(&id, user)|
+        {
+            let key = (if by_name { user.name.clone() } else { String::new() }, id);
+            (key, json!({"id": id, "name": user.name, "revision": user.revision}))
+        })
+        .collect();
+    paginate(items, query)
+}
+
+fn list_groups(groups: &HashMap<Id, Group>, organization_id: Id, query: &ListQuery) -> Result<Value, &'static str>
+{
+    match query.sort.as_deref()
+    {
+        None | Some("id") => (),
+        Some(_) => return Err("wrong sort field"),
+    };
+    // Архивные группы видны только с state=archived.
+    let (archived, closed) = match query.state.as_deref()
+    {
+        None => (false, None),
+        Some("open") => (false, Some(false)),
+        Some("closed") => (false, Some(true)),
+        Some("archived") => (true, None),
+        Some(_) => return Err("wrong state"),
+    };
+    let items = groups.iter()
+        .filter(|&(_, group)| group.organization_id == organization_id)
+        .filter(|&(_, group)| group.archived_at.is_some() == archived)
+        .filter(|&(_, group)| closed.is_none() || closed == Some(group.is_closed))
+        .map(|(&id, group)| ((String::new(), id), json!({"id": id, "is_closed": group.is_closed, "revision": group.revision})))
+        .collect();
+    paginate(items, query)
+}
+
+// Возвращает ID нового пользователя и его токен доступа.
+fn create_user(db: &mut DataBase, name: String, organization_id: Id) -> Result<(Id, String), &'static str>
+{
+    if name.is_empty()
+    {
+        return Err("bad name");
+    }
+    let id = db.users_max_id;
+    db.users.insert(id, User { name, revision: 1, credentials: None, is_operator: false, organization_id, is_org_admin: false });
+    db.users_max_id += 1;
+    let token = auth::generate_token();
+    db.tokens.insert(token.clone(), id);
+    Ok((id, token))
+}
+
+fn is_login_taken(db: &DataBase, login: &str) -> bool
+{
+    db.users.values().any(|user| matches!(&user.credentials, Some(credentials) if credentials.login == login))
+}
+
+// Проверяет логин и пароль и считает хэш пароля. Вызывается без блокировки состояния,
+// уникальность логина проверяется отдельно под блокировкой.
+fn new_credentials(login: Option<&str>, password: Option<&str>) -> Result<Option<auth::Credentials>, &'static str>
+{
+    match (login, password)
+    {
+        (None, None) => Ok(None),
+        (Some(login), Some(password)) =>
+        {
+            if login.is_empty()
+            {
+                Err("bad login")
+            }
+            else if password.chars().count() < auth::MIN_PASSWORD_LEN
+            {
+                Err("password is too short")
+            }
+            else
+            {
+                Ok(Some(auth::Credentials::new(login.to_string(), password)))
+            }
+        },
+        _ => Err("login and password must be given together"),
+    }
+}
 
+// Создает пользователей из массива `users` в организации organization_id, как /user/create_many.
+fn users_create_many(object: &Map<String, Value>, state: &Arc<Mutex<DataBase>>, organization_id: Id) -> Response
+{
+    let users = object.get("users").and_then(|x| x.as_array()).cloned().unwrap_or_default();
+    let partial = object.get("partial").and_then(|x| x.as_bool()).unwrap_or(false);
 
+    run_bulk(state, &users, partial, |_| None, |db, item|
+    {
+        let name = item.get("name").and_then(|x| x.as_str()).ok_or("bad name")?;
+        let (id, token) = create_user(db, name.to_string(), organization_id)?;
+        Ok(json!({"id": id, "token": token}))
+    })
+}
 
-fn user_create(input_obj: &Map<String, Value>, state: &Arc<Mutex<DataBase>>) -> Response
+fn user_create(input_obj: &Map<String, Value>, state: &Arc<Mutex<DataBase>>, organization_id: Id) -> Response
 {
     let name: String = get_field(input_obj, "name");
-    if name.len() > 0
+    let login = input_obj.get("login").and_then(|x| x.as_str());
+    let password = input_obj.get("password").and_then(|x| x.as_str());
+    let credentials = match new_credentials(login, password)
+    {
+        Ok(credentials) => credentials,
+        Err(msg) => return response_error(msg),
+    };
+
+    let mut guard = state.lock().unwrap();
+    if let Some(credentials) = &credentials
+    {
+        if is_login_taken(&guard, &credentials.login)
+        {
+            return response_error("login is taken");
+        }
+    }
+    match create_user(&mut guard, name, organization_id)
+    {
+        Ok((id, token)) =>
+        {
+            guard.users.get_mut(&id).unwrap().credentials = credentials;
+            response_data(json!({"id": id, "token": token}))
+        },
+        Err(msg) => response_error(msg),
+    }
+}
+
+fn join_group(db: &mut DataBase, user_id: Id, group_id: Id) -> Result<(), &'static str>
+{
+    match db.groups.get(&group_id)
+    {
+        None => Err("no such group"),
+        Some(_) if !is_group_visible(db, user_id, group_id) && db.users.contains_key(&user_id) => Err("no such group"),
+        Some(group) if group.archived_at.is_some() => Err("group is archived"),
+        Some(group) if group.is_closed => Err("group is closed"),
+        Some(group) if is_join_deadline_passed(group) => Err("join deadline has passed"),
+        Some(_) =>
+        {
+            if !db.users.contains_key(&user_id)
+            {
+                return Err("no such user");
+            }
+            let user_group_id = UserGroupId{user_id, group_id};
+            if db.bans.contains(&user_group_id)
+            {
+                return Err("user is banned from group");
+            }
+            db.join_requests.remove(&user_group_id);
+            match db.user_groups.entry(user_group_id)
+            {
+                Entry::Occupied(_) => Err("user already in group"),
+                Entry::Vacant(entry) =>
+                {
+                    entry.insert(UserGroupProps::new(Access::User));
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+// Для групп с одобрением создает заявку на вступление, для остальных сразу добавляет в группу.
+// Возвращает true, если пользователь добавлен.
+fn join_or_request(db: &mut DataBase, user_id: Id, group_id: Id) -> Result<bool, &'static str>
+{
+    match db.groups.get(&group_id)
     {
-        let mut guard = state.lock().unwrap();
-        let id = guard.users_max_id;
-        guard.users.insert(id, name);
-        guard.users_max_id += 1;
+        Some(group) if group.approval_required && !group.is_closed && !is_join_deadline_passed(group) && is_group_visible(db, user_id, group_id) =>
+        {
+            if !db.users.contains_key(&user_id)
+            {
+                return Err("no such user");
+            }
+            let user_group_id = UserGroupId{user_id, group_id};
+            if db.user_groups.contains_key(&user_group_id)
+            {
+                return Err("user already in group");
+            }
+            if db.bans.contains(&user_group_id)
+            {
+                return Err("user is banned from group");
+            }
+            match db.join_requests.entry(user_group_id)
+            {
+                Entry::Occupied(_) => Err("join request already exists"),
+                Entry::Vacant(entry) =>
+                {
+                    entry.insert(Utc::now());
+                    Ok(false)
+                }
+            }
+        },
+        _ => join_group(db, user_id, group_id).map(|()| true),
+    }
+}
+
+// Исключает участника из группы. Общие проверки /group/quit и /group/kick.
+fn leave_group(db: &mut DataBase, user_group_id: &UserGroupId) -> Result<(), &'static str>
+{
+    match db.user_groups.get(user_group_id)
+    {
+        None => Err("user does not belong to this group"),
+        Some(props) if props.access_level == Access::Owner => Err("owner cannot leave the group, transfer ownership first"),
+        Some(props) if props.access_level.is_admin() && count_admins(user_group_id.group_id, &db.user_groups) < 2 =>
+        {
+            Err("user is only one Admin in this group")
+        },
+        Some(_) if db.groups[&user_group_id.group_id].archived_at.is_some() => Err("group is archived"),
+        Some(_) if db.groups[&user_group_id.group_id].is_closed => Err("group is closed"),
+        Some(_) =>
+        {
+            db.user_groups.remove(user_group_id);
+            Ok(())
+        },
+    }
+}
+
+// ID в элементах массивов принимается и числом, и числом в форме строки.
+fn parse_id(value: &Value) -> Option<Id>
+{
+    match value
+    {
+        Value::Number(number) => number.as_u64()?.try_into().ok(),
+        Value::String(string) => string.parse().ok(),
+        _ => None,
+    }
+}
+
+// Выполняет операцию для каждого элемента массива под одной блокировкой.
+// Операции применяются к копии базы, которая заменяет оригинал, если ошибок нет или включен
+// частичный режим `partial`. Возвращает результаты по каждому элементу. Проверка check
+// выполняется под той же блокировкой до первого элемента: если она вернула ответ, ничего
+// не применяется и возвращается он.
+fn run_bulk<C, F>(state: &Arc<Mutex<DataBase>>, items: &[Value], partial: bool, check: C, mut operation: F) -> Response
+where
+    C: FnOnce(&DataBase) -> Option<Response>,
+    F: FnMut(&mut DataBase, &Value) -> Result<Value, &'static str>,
+{
+    let mut guard = state.lock().unwrap();
+    if let Some(response) = check(&guard)
+    {
+        return response;
+    }
+    let mut copy = guard.clone();
+    let mut failed = false;
+    let results: Vec<Value> = items.iter().enumerate().map(|(index, item)|
+        match operation(&mut copy, item)
+        {
+            Ok(Value::Object(mut result)) =>
+            {
+                result.insert("index".to_string(), json!(index));
+                Value::Object(result)
+            },
+            Ok(_) => json!({"index": index}),
+            Err(msg) =>
+            {
+                failed = true;
+                json!({"index": index, "error": msg})
+            },
+        }
+    ).collect();
 
-        response_data(json!({"id": id}))
+    if failed && !partial
+    {
+        Response::builder(400)
+            .body(tide::Body::from_json(&json!({"applied": false, "results": results})).unwrap())
+            .build()
     }
     else
     {
-        response_error("bad name")
+        *guard = copy;
+        response_data(json!({"applied": true, "results": results}))
     }
 }
 
 fn does_user_belong_to_group(user_id: Id, group_id: Id, user_groups: &HashMap<UserGroupId,UserGroupProps>) -> bool
 {
-    return user_groups.contains_key(&UserGroupId { user_id, group_id });
+    user_groups.contains_key(&UserGroupId { user_id, group_id })
 }
 
-fn count_admins(group_id: Id, user_groups: &HashMap<UserGroupId, UserGroupProps>) ->usize
+fn organization_of(db: &DataBase, user_id: Id) -> Option<Id>
 {
-    let iter = user_groups.into_iter();
-    let collection = iter.filter(|&x| x.0.group_id == group_id && x.1.access_level == Access::Admin);
-    return collection.count();
+    db.users.get(&user_id).map(|user| user.organization_id)
 }
-fn is_admin(user_id: Id, group_id: Id, map: &HashMap<UserGroupId, UserGroupProps>) -> bool
+
+// Группы чужих организаций для пользователя как будто не существуют.
+fn is_group_visible(db: &DataBase, user_id: Id, group_id: Id) -> bool
 {
-    map.get(
-        &UserGroupId {
-            user_id,
-            group_id,
-        }
-    ).unwrap().access_level == Access::Admin
+    match (organization_of(db, user_id), db.groups.get(&group_id))
+    {
+        (Some(organization_id), Some(group)) => group.organization_id == organization_id,
+        _ => false,
+    }
 }
 
-fn get_secret_santas(group: &Vec<Id>) -> Vec<Id>
+// Управлять организацией может ее администратор, а своей организацией - и оператор сервера.
+fn is_org_admin(db: &DataBase, user_id: Id) -> bool
 {
-    //Пользователю присваивается santa_id = Id предыдущего в group
-    //Первому присваивается последний
-    let mut result = Vec::with_capacity(group.len());
-    result.push(0);
-    for i in 0..(group.len() - 1)
+    db.users.get(&user_id).is_some_and(|user| user.is_org_admin || user.is_operator)
+}
+
+// Создает организацию и ее первого администратора. Возвращает ID организации, ID и токен администратора.
+fn create_organization(db: &mut DataBase, name: String, admin_name: String) -> Result<(Id, Id, String), &'static str>
+{
+    if name.is_empty()
     {
-        result.push(group[i]);
+        return Err("bad name");
     }
-    result[0] = group[group.len() - 1];
-    result
+    let organization_id = db.organizations_max_id;
+    let (admin_id, token) = create_user(db, admin_name, organization_id)?;
+    db.users.get_mut(&admin_id).unwrap().is_org_admin = true;
+    db.organizations.insert(organization_id, Organization { name, revision: 1 });
+    db.organizations_max_id += 1;
+    Ok((organization_id, admin_id, token))
 }
 
-fn main() -> Result<(), std::io::Error> 
+fn organization_info(db: &DataBase, organization_id: Id) -> Value
 {
-    let f = async {
-        let data = DataBase
+    let organization = &db.organizations[&organization_id];
+    let mut admins: Vec<Id> = db.users.iter()
+        .filter(|&(_, user)| user.organization_id == organization_id && user.is_org_admin)
+        .map(|(&id, _)| id)
+        .collect();
+    admins.sort();
+    json!({
+        "id": organization_id,
+        "name": organization.name,
+        "revision": organization.revision,
+        "admins": admins,
+        "users": db.users.values().filter(|user| user.organization_id == organization_id).count(),
+        "groups": db.groups.values().filter(|group| group.organization_id == organization_id).count(),
+    })
+}
+
+fn response_org_admin_only() -> Response
+{
+    response_error_with_status(403, "organization admin only")
+}
+
+// Назначает или снимает администратора организации. Общий обработчик /organization/make_admin и /organization/unadmin.
+async fn set_org_admin(mut request: Request<Arc<Mutex<DataBase>>>, is_admin: bool) -> tide::Result
+{
+    let body: Value = request.body_json().await?;
+    let object = body.as_object().unwrap();
+    let user_id: Id = get_field(object, "user_id");
+    let admin_id = auth::acting_user(&request);
+    let if_match = if_match(&request);
+
+    let mut guard = request.state().lock().unwrap();
+    if !is_org_admin(&guard, admin_id)
+    {
+        return Ok(response_org_admin_only());
+    }
+    let organization_id = guard.users[&admin_id].organization_id;
+    let admins = guard.users.values().filter(|user| user.organization_id == organization_id && user.is_org_admin).count();
+    Ok(match guard.users.get_mut(&user_id)
+    {
+        Some(user) if user.organization_id == organization_id =>
         {
-            users: HashMap::new(),
-            users_max_id: 0,
-            groups: HashMap::new(),
-            groups_max_id: 0,
-            user_groups: HashMap::new(),
-        };
-        let state = Arc::new(Mutex::new(data));
-        let mut app = tide::with_state(state);
+            if !revision_matches(&if_match, user.revision)
+            {
+                response_precondition_failed(user.revision)
+            }
+            else if user.is_org_admin == is_admin
+            {
+                response_error(if is_admin { "user is already an admin" } else { "user is not an admin" })
+            }
+            else if !is_admin && admins < 2
+            {
+                response_error("user is the only admin of the organization")
+            }
+            else
+            {
+                user.is_org_admin = is_admin;
+                user.revision += 1;
+                with_etag(response_empty(), user.revision)
+            }
+        },
+        _ => response_error("no such user"),
+    })
+}
 
-        // Routes
-        app.at("/users")
-            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
-                let guard = request.state().lock().unwrap();
-                Ok(json!(guard.users))
-            });
-        app.at("/groups")
-            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
-                let guard = request.state().lock().unwrap();
-                Ok(json!(guard.groups))
-            });
-        app.at("/user/create")
-            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
-                let body: Value = request.body_json().await?;
-                let input_obj = body.as_object().unwrap();
-                Ok(user_create(input_obj, request.state()))
+fn is_drawn(db: &DataBase, group_id: Id) -> bool
+{
+    db.user_groups.iter().any(|(key, props)| key.group_id == group_id && props.santa_id.is_some())
+}
+
+// Пары (santa_id, recipient_id) группы по возрастанию santa_id.
+fn assignments(db: &DataBase, group_id: Id) -> Vec<(Id, Id)>
+{
+    let mut assignments: Vec<(Id, Id)> = db.user_groups.iter()
+        .filter(|&(key, _)| key.group_id == group_id)
+        .filter_map(|(key, props)| props.santa_id.map(|recipient_id| (key.user_id, recipient_id)))
+        .collect();
+    assignments.sort();
+    assignments
+}
+
+// Архивную группу можно только читать и удалить. Группы чужих организаций не считаются архивными,
+// чтобы ответ не выдавал их существование.
+fn is_archived(db: &DataBase, user_id: Id, group_id: Id) -> bool
+{
+    is_group_visible(db, user_id, group_id) && db.groups[&group_id].archived_at.is_some()
+}
+
+fn response_archived() -> Response
+{
+    response_error_with_status(409, "group is archived")
+}
+
+// Переводит группу в архив.
+fn archive_group(db: &mut DataBase, group_id: Id, now: DateTime<Utc>)
+{
+    let group = db.groups.get_mut(&group_id).unwrap();
+    group.archived_at = Some(now);
+    group.is_closed = true;
+    group.draw_at = None;
+    group.revision += 1;
+}
+
+// Удаляет из архивной группы участников, их списки желаний и пары, приглашения, заявки и баны,
+// оставляя обезличенную статистику.
+fn purge_group(db: &mut DataBase, group_id: Id, now: DateTime<Utc>)
+{
+    let members: Vec<&UserGroupProps> = db.user_groups.iter()
+        .filter(|&(key, _)| key.group_id == group_id)
+        .map(|(_, props)| props)
+        .collect();
+    let stats = GroupStats {
+        members: members.len(),
+        wishlists: members.iter().filter(|props| !props.wishlist.is_empty()).count(),
+        was_drawn: members.iter().any(|props| props.santa_id.is_some()),
+    };
+    db.user_groups.retain(|key, _| key.group_id != group_id);
+    db.invites.retain(|_, invite| invite.group_id != group_id);
+    db.join_requests.retain(|key, _| key.group_id != group_id);
+    db.bans.retain(|key| key.group_id != group_id);
+    let group = db.groups.get_mut(&group_id).unwrap();
+    group.commitment = None;
+    group.stats = Some(stats);
+    group.purged_at = Some(now);
+    group.revision += 1;
+}
+
+// Планировщик закрывает группу с небольшой задержкой, вступление запрещено сразу после срока.
+fn is_join_deadline_passed(group: &Group) -> bool
+{
+    group.join_deadline.is_some_and(|deadline| deadline <= Utc::now())
+}
+
+// Меньше двух участников - дарить некому, а единственный участник стал бы Сантой сам себе.
+const MIN_DRAW_MEMBERS: usize = 2;
+
+// Почему в еще не разыгранной группе нельзя провести жеребьевку. Общая проверка для
+// /group/secret_santa и планировщика.
+fn draw_problem(db: &DataBase, group_id: Id) -> Option<&'static str>
+{
+    if db.user_groups.keys().filter(|key| key.group_id == group_id).count() < MIN_DRAW_MEMBERS
+    {
+        Some("not enough members")
+    }
+    // Пары такой жеребьевки сразу видны всем через /group/assignments.
+    else if is_revealed(&db.groups[&group_id])
+    {
+        Some("exchange date has passed")
+    }
+    else
+    {
+        None
+    }
+}
+
+// Проводит жеребьевку и закрывает группу. Возвращает новую версию группы.
+fn draw(db: &mut DataBase, group_id: Id) -> Revision
+{
+    let group = db.groups.get_mut(&group_id).unwrap();
+    group.is_closed = true;
+    group.draw_at = None;
+    group.scheduled_draw_error = None;
+    group.revision += 1;
+    let revision = group.revision;
+    let group: Vec<Id> = db.user_groups.keys().filter_map(|key|
+        match key.group_id == group_id
+        {
+            true => Some(key.user_id),
+            false => None,
+        }
+    ).collect();
+    let santas = get_secret_santas(&group);
+    for i in 0..group.len()
+    {
+        let user_group_props = db.user_groups.get_mut(&UserGroupId{user_id: group[i], group_id}).unwrap();
+        user_group_props.santa_id = Some(santas[i]);
+        user_group_props.revision += 1;
+    }
+    let commitment = Commitment::new(&assignments(db, group_id));
+    db.groups.get_mut(&group_id).unwrap().commitment = Some(commitment);
+    revision
+}
+
+// Пары раскрываются всем участникам на следующий день после даты обмена.
+fn is_revealed(group: &Group) -> bool
+{
+    group.exchange_date.is_some_and(|date| Utc::now().date_naive() > date)
+}
+
+// Можно ли поставить группе дату обмена date. Иначе администратор раскрыл бы пары раньше
+// времени, перенеся дату в прошлое, а после жеребьевки - и на любой более ранний день.
+fn check_exchange_date(group: &Group, drawn: bool, date: Option<NaiveDate>, today: NaiveDate) -> Result<(), (u16, &'static str)>
+{
+    if drawn && date != group.exchange_date
+    {
+        Err((409, "exchange date cannot be changed after the draw"))
+    }
+    else if date.is_some_and(|date| date < today)
+    {
+        Err((400, "exchange date cannot be in the past"))
+    }
+    else
+    {
+        Ok(())
+    }
+}
+
+// Этап группы: набор участников, после жеребьевки, после раскрытия пар.
+fn group_phase(db: &DataBase, group_id: Id) -> &'static str
+{
+    if db.groups[&group_id].archived_at.is_some()
+    {
+        "archived"
+    }
+    else if !is_drawn(db, group_id)
+    {
+        "open"
+    }
+    else if is_revealed(&db.groups[&group_id])
+    {
+        "revealed"
+    }
+    else
+    {
+        "drawn"
+    }
+}
+
+fn count_admins(group_id: Id, user_groups: &HashMap<UserGroupId, UserGroupProps>) ->usize
+{
+    let iter = user_groups.iter();
+    let collection = iter.filter(|&x| x.0.group_id == group_id && x.1.access_level.is_admin());
+    collection.count()
+}
+fn is_admin(user_id: Id, group_id: Id, map: &HashMap<UserGroupId, UserGroupProps>) -> bool
+{
+    map.get(
+        &UserGroupId {
+            user_id,
+            group_id,
+        }
+    ).is_some_and(|props| props.access_level.is_admin())
+}
+
+fn has_permission(db: &DataBase, user_id: Id, group_id: Id, permission: Permission) -> bool
+{
+    let (Some(props), Some(group)) = (db.user_groups.get(&UserGroupId{user_id, group_id}), db.groups.get(&group_id)) else
+    {
+        return false;
+    };
+    match props.access_level
+    {
+        Access::Owner => true,
+        Access::Admin => group.admin_permissions.contains(&permission),
+        Access::User => group.user_permissions.contains(&permission),
+    }
+}
+
+fn sorted_permissions(permissions: &HashSet<Permission>) -> Vec<&'static str>
+{
+    let mut names: Vec<&'static str> = permissions.iter().map(|permission| permission.name()).collect();
+    names.sort();
+    names
+}
+
+// Делает участника владельцем группы, прежний владелец становится администратором.
+// Возвращает новую версию группы.
+fn transfer_ownership(db: &mut DataBase, group_id: Id, new_owner_id: Id) -> Result<Revision, &'static str>
+{
+    if !db.groups.contains_key(&group_id)
+    {
+        return Err("no such group");
+    }
+    match db.user_groups.get(&UserGroupId{user_id: new_owner_id, group_id})
+    {
+        None => return Err("user isn't a member of the group"),
+        Some(props) if props.access_level == Access::Owner => return Err("user is already the owner"),
+        Some(_) => (),
+    }
+    for (key, props) in db.user_groups.iter_mut()
+    {
+        if key.group_id == group_id && props.access_level == Access::Owner
+        {
+            props.access_level = Access::Admin;
+            props.revision += 1;
+        }
+    }
+    let props = db.user_groups.get_mut(&UserGroupId{user_id: new_owner_id, group_id}).unwrap();
+    props.access_level = Access::Owner;
+    props.revision += 1;
+    let group = db.groups.get_mut(&group_id).unwrap();
+    group.revision += 1;
+    Ok(group.revision)
+}
+
+fn response_missing_permission(permission: Permission) -> Response
+{
+    response_error_with_status(403, &format!("missing permission {}", permission.name()))
+}
+
+fn get_secret_santas(group: &[Id]) -> Vec<Id>
+{
+    //Пользователю присваивается santa_id = Id предыдущего в group
+    //Первому присваивается последний
+    let mut result = Vec::with_capacity(group.len());
+    result.push(0);
+    for &id in &group[..(group.len() - 1)]
+    {
+        result.push(id);
+    }
+    result[0] = group[group.len() - 1];
+    result
+}
+
+// Оператор сервера с заранее известным токеном, например для групп, владелец которых ушел.
+// Если оператор уже есть в сохраненной базе, ему просто выдается этот токен.
+// Отдает operator_token из настроек оператору, заведенному для него. Прежний токен из настроек
+// перестает действовать, остальные токены операторов не меняются.
+fn ensure_operator(db: &mut DataBase, token: String)
+{
+    let is_operator = |db: &DataBase, id: &Id| db.users.get(id).is_some_and(|user| user.is_operator);
+    let configured = db.configured_operator.take().filter(|configured| is_operator(db, &configured.user_id));
+    let id = match configured
+    {
+        Some(configured) =>
+        {
+            if db.tokens.get(&configured.token) == Some(&configured.user_id)
+            {
+                db.tokens.remove(&configured.token);
+            }
+            configured.user_id
+        },
+        // База, сохраненная до появления configured_operator: токен уже мог принадлежать оператору.
+        None => match db.tokens.get(&token).copied().filter(|id| is_operator(db, id))
+        {
+            Some(id) => id,
+            None =>
+            {
+                let (id, _) = create_user(db, "operator".to_string(), DEFAULT_ORGANIZATION).unwrap();
+                db.users.get_mut(&id).unwrap().is_operator = true;
+                id
+            },
+        },
+    };
+    db.tokens.insert(token.clone(), id);
+    db.configured_operator = Some(ConfiguredOperator { user_id: id, token });
+}
+
+fn main() -> Result<(), std::io::Error>
+{
+    let (args, config) = match config::Args::parse(std::env::args().skip(1))
+        .and_then(|args| config::Config::load(&args).map(|config| (args, config)))
+    {
+        Ok(parsed) => parsed,
+        Err(msg) =>
+        {
+            eprintln!("config error: {}", msg);
+            std::process::exit(2);
+        },
+    };
+    if args.print_config
+    {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+    let result = match args.command.as_str()
+    {
+        "help" =>
+        {
+            println!("{}", manage::USAGE);
+            return Ok(());
+        },
+        "serve" if args.command_args.is_empty() => return serve(config),
+        "serve" => Err(manage::Failure::Usage(format!("unknown argument {}", args.command_args[0]))),
+        command => manage::run(command, &args.command_args, &config),
+    };
+    match result
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => std::process::exit(1),
+        Err(manage::Failure::Usage(msg)) =>
+        {
+            eprintln!("{}\n\n{}", msg, manage::USAGE);
+            std::process::exit(2);
+        },
+        Err(manage::Failure::Failed(msg)) =>
+        {
+            eprintln!("{}: {}", args.command, msg);
+            std::process::exit(1);
+        },
+    }
+}
+
+fn serve(config: config::Config) -> Result<(), std::io::Error>
+{
+    logging::init(config.log_level(), config.log_format);
+
+    let storage = Arc::new(storage::Storage::new(&config.storage));
+    let mut data = storage.load()?.unwrap_or_else(DataBase::new);
+    if let Some(token) = config.operator_token.clone()
+    {
+        ensure_operator(&mut data, token);
+    }
+    let state = Arc::new(Mutex::new(data));
+    let shutdown = shutdown::Shutdown::new();
+    let metrics = metrics::Metrics::new();
+    let stop = shutdown::signal();
+    let persist = storage::Persist::new(storage.clone());
+    let scheduler = scheduler::Scheduler::spawn(
+        state.clone(),
+        persist.clone(),
+        metrics.clone(),
+        config.scheduler_interval(),
+        config.retention(),
+    );
+
+    let f = async {
+        let mut app = tide::with_state(state.clone());
+        app.with(metrics.clone());
+        app.with(logging::RequestLog);
+        app.with(auth::TrustedProxies::new(config.trusted_proxies.clone()));
+        app.with(shutdown.clone());
+        app.with(limits::Timeout::new(config.request_timeout()));
+        if config.features.rate_limit
+        {
+            app.with(rate_limit::RateLimit::new(
+                rate_limit::Quota::per_minute(config.limits.read_per_minute),
+                rate_limit::Quota::per_minute(config.limits.write_per_minute),
+            ));
+        }
+        app.with(limits::BodyLimit::new(
+            config.limits.max_body_bytes,
+            config.limits.route_body_bytes.clone(),
+            config.limits.max_json_depth,
+        ));
+        if config.features.idempotency
+        {
+            app.with(idempotency::Idempotency::new(config.idempotency_window()));
+        }
+        app.with(persist.clone());
+
+        let metrics_for_route = metrics.clone();
+        metrics.route(&mut app, "/metrics")
+            .get(move |request: Request<Arc<Mutex<DataBase>>>| {
+                let metrics = metrics_for_route.clone();
+                async move {
+                    let gauges = {
+                        let guard = request.state().lock().unwrap();
+                        // Архивная группа тоже закрыта, но считается отдельно.
+                        let archived_groups = guard.groups.values().filter(|group| group.archived_at.is_some()).count();
+                        let closed_groups = guard.groups.values().filter(|group| group.is_closed && group.archived_at.is_none()).count();
+                        metrics::Gauges {
+                            users: guard.users.len(),
+                            open_groups: guard.groups.len() - closed_groups - archived_groups,
+                            closed_groups,
+                            archived_groups,
+                            memberships: guard.user_groups.len(),
+                        }
+                    };
+                    Ok(Response::builder(200)
+                        .body(metrics.render(&gauges))
+                        .content_type("text/plain; version=0.0.4")
+                        .build())
+                }
+            });
+
+        // Routes
+        // Проверки для балансировщика нагрузки.
+        metrics.route(&mut app, "/healthz")
+            .get(|_| async move {
+                Ok(response_data(json!({"status": "ok"})))
+            });
+        let readiness_storage = storage.clone();
+        metrics.route(&mut app, "/readyz")
+            .get(move |_| {
+                let storage = readiness_storage.clone();
+                async move {
+                    Ok(match storage.check_writable()
+                    {
+                        Ok(()) => response_data(json!({"status": "ready"})),
+                        Err(err) => response_error_with_status(503, &format!("storage is not writable: {}", err)),
+                    })
+                }
+            });
+        metrics.route(&mut app, "/version")
+            .get(|_| async move {
+                Ok(response_data(json!({
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "commit": env!("GIT_COMMIT"),
+                    "schema_version": storage::SCHEMA_VERSION,
+                })))
+            });
+        metrics.route(&mut app, "/users")
+            .with(auth::Authentication)
+            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
+                let query: ListQuery = match request.query()
+                {
+                    Ok(query) => query,
+                    Err(_) => return Ok(response_error("wrong query")),
+                };
+                let guard = request.state().lock().unwrap();
+                let organization_id = organization_of(&guard, auth::acting_user(&request)).unwrap_or(DEFAULT_ORGANIZATION);
+                Ok(match list_users(&guard.users, organization_id, &query)
+                {
+                    Ok(page) => response_data(page),
+                    Err(msg) => response_error(msg),
+                })
+            });
+        metrics.route(&mut app, "/groups")
+            .with(auth::Authentication)
+            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
+                let query: ListQuery = match request.query()
+                {
+                    Ok(query) => query,
+                    Err(_) => return Ok(response_error("wrong query")),
+                };
+                let guard = request.state().lock().unwrap();
+                let organization_id = organization_of(&guard, auth::acting_user(&request)).unwrap_or(DEFAULT_ORGANIZATION);
+                Ok(match list_groups(&guard.groups, organization_id, &query)
+                {
+                    Ok(page) => response_data(page),
+                    Err(msg) => response_error(msg),
+                })
+            });
+        metrics.route(&mut app, "/user/create")
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let input_obj = body.as_object().unwrap();
+                Ok(user_create(input_obj, request.state(), DEFAULT_ORGANIZATION))
+            });
+        metrics.route(&mut app, "/user/create_many")
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                // Каждый созданный пользователь считается отдельным изменяющим запросом, один запрос уже учтен.
+                let count = object.get("users").and_then(|x| x.as_array()).map_or(0, |users| users.len());
+                if let Some(response) = rate_limit::charge(&request, count.saturating_sub(1))
+                {
+                    return Ok(response);
+                }
+                Ok(users_create_many(object, request.state(), DEFAULT_ORGANIZATION))
+            });
+        metrics.route(&mut app, "/user/login")
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let login = object.get("login").and_then(|x| x.as_str()).unwrap_or_default();
+                let password = object.get("password").and_then(|x| x.as_str()).unwrap_or_default();
+
+                let found = {
+                    let guard = request.state().lock().unwrap();
+                    guard.users.iter().find_map(|(&id, user)| match &user.credentials
+                    {
+                        Some(credentials) if credentials.login == login => Some((id, credentials.clone())),
+                        _ => None,
+                    })
+                };
+                // Хэш пароля считается без блокировки состояния.
+                Ok(match found
+                {
+                    Some((user_id, credentials)) if credentials.verify(password) =>
+                    {
+                        let session_id = auth::generate_token();
+                        let mut guard = request.state().lock().unwrap();
+                        guard.sessions.retain(|_, session| !session.is_expired());
+                        guard.sessions.insert(session_id.clone(), auth::Session::new(user_id));
+                        let mut response = response_data(json!({"id": user_id}));
+                        auth::set_session_cookie(&mut response, &session_id);
+                        response
+                    },
+                    _ => response_error_with_status(401, "wrong login or password"),
+                })
+            });
+        metrics.route(&mut app, "/user/logout")
+            .with(auth::Authentication)
+            .post(|request: Request<Arc<Mutex<DataBase>>>| async move {
+                if let Some(session_id) = auth::session_id(&request)
+                {
+                    request.state().lock().unwrap().sessions.remove(&session_id);
+                }
+                let mut response = response_empty();
+                auth::clear_session_cookie(&mut response);
+                Ok(response)
+            });
+        metrics.route(&mut app, "/user/password")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let user_id = auth::acting_user(&request);
+                let old_password = object.get("old_password").and_then(|x| x.as_str()).unwrap_or_default();
+                let new_password = object.get("new_password").and_then(|x| x.as_str());
+                let current_session = auth::session_id(&request);
+
+                let old_credentials = request.state().lock().unwrap().users.get(&user_id).and_then(|user| user.credentials.clone());
+                // Если пароля еще нет, вместе с ним задается логин.
+                let login = match &old_credentials
+                {
+                    Some(credentials) =>
+                    {
+                        if !credentials.verify(old_password)
+                        {
+                            return Ok(response_error_with_status(403, "wrong password"));
+                        }
+                        Some(credentials.login.as_str())
+                    },
+                    None => object.get("login").and_then(|x| x.as_str()),
+                };
+                let credentials = match new_credentials(login, new_password)
+                {
+                    Ok(Some(credentials)) => credentials,
+                    Ok(None) => return Ok(response_error("login and password must be given together")),
+                    Err(msg) => return Ok(response_error(msg)),
+                };
+
+                let mut guard = request.state().lock().unwrap();
+                if old_credentials.is_none() && is_login_taken(&guard, &credentials.login)
+                {
+                    return Ok(response_error("login is taken"));
+                }
+                // Остальные сессии пользователя завершаются.
+                guard.sessions.retain(|id, session| session.user_id != user_id || Some(id) == current_session.as_ref());
+                Ok(match guard.users.get_mut(&user_id)
+                {
+                    None => response_error("no such user"),
+                    Some(user) =>
+                    {
+                        user.credentials = Some(credentials);
+                        user.revision += 1;
+                        with_etag(response_empty(), user.revision)
+                    },
+                })
+            });
+        metrics.route(&mut app, "/group/create")
+            .with(auth::Authentication)
+            .post(|request: Request<Arc<Mutex<DataBase>>>| async move {
+                let creator_id = auth::acting_user(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                Ok(match organization_of(&guard, creator_id)
+                {
+                    None => response_error("no such user"),
+                    Some(organization_id) =>
+                    {
+                        let id = guard.groups_max_id;
+                        guard.groups.insert(id, Group::new(organization_id));
+                        guard.groups_max_id += 1;
+                        guard.user_groups.insert(
+                            UserGroupId
+                            {
+                                user_id: creator_id,
+                                group_id: id,
+                            },
+                            UserGroupProps::new(Access::Owner)
+                        );
+                        response_data(json!({"group_id": id}))
+                    },
+                })
+            });
+        metrics.route(&mut app, "/group/join")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let value: Value = request.body_json().await.unwrap();
+                let object = value.as_object().unwrap();
+                let user_id = auth::acting_user(&request);
+                let group_id = get_field(object, "group_id");
+
+                let mut guard = request.state().lock().unwrap();
+                Ok(match join_or_request(&mut guard, user_id, group_id)
+                {
+                    Ok(true) => response_empty(),
+                    Ok(false) => Response::builder(202)
+                        .body(tide::Body::from_json(&json!({"status": "pending"})).unwrap())
+                        .build(),
+                    Err(msg) => response_error(msg),
+                })
+            });
+        metrics.route(&mut app, "/group/requests/:group_id")
+            .with(auth::Authentication)
+            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
+                let raw_id = request.param("group_id")?;
+                let admin_id = auth::acting_user(&request);
+                let guard = request.state().lock().unwrap();
+                Ok(match raw_id.parse().ok()
+                {
+                    None => response_error("wrong format group_id"),
+                    Some(group_id) =>
+                    {
+                        if !is_group_visible(&guard, admin_id, group_id)
+                        {
+                            response_error("no such group")
+                        }
+                        else if !has_permission(&guard, admin_id, group_id, Permission::Approve)
+                        {
+                            response_missing_permission(Permission::Approve)
+                        }
+                        else
+                        {
+                            let mut requests: Vec<(&DateTime<Utc>, Id)> = guard.join_requests.iter()
+                                .filter(|&(key, _)| key.group_id == group_id)
+                                .map(|(key, created_at)| (created_at, key.user_id))
+                                .collect();
+                            requests.sort();
+                            let requests: Vec<Value> = requests.into_iter()
+                                .map(|(created_at, user_id)| json!({
+                                    "user_id": user_id,
+                                    "name": guard.users.get(&user_id).map(|user| &user.name),
+                                    "created_at": created_at,
+                                }))
+                                .collect();
+                            response_data(json!(requests))
+                        }
+                    },
+                })
+            });
+        metrics.route(&mut app, "/group/requests/approve")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let group_id: Id = get_field(object, "group_id");
+                let user_id: Id = get_field(object, "user_id");
+                let admin_id = auth::acting_user(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                if is_archived(&guard, auth::acting_user(&request), group_id)
+                {
+                    return Ok(response_archived());
+                }
+                Ok(if !guard.join_requests.contains_key(&UserGroupId{user_id, group_id})
+                {
+                    response_error("no such join request")
+                }
+                else if !has_permission(&guard, admin_id, group_id, Permission::Approve)
+                {
+                    response_missing_permission(Permission::Approve)
+                }
+                else
+                {
+                    match join_group(&mut guard, user_id, group_id)
+                    {
+                        Ok(()) => response_empty(),
+                        Err(msg) => response_error(msg),
+                    }
+                })
+            });
+        metrics.route(&mut app, "/group/requests/reject")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let group_id: Id = get_field(object, "group_id");
+                let user_id: Id = get_field(object, "user_id");
+                let admin_id = auth::acting_user(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                if is_archived(&guard, auth::acting_user(&request), group_id)
+                {
+                    return Ok(response_archived());
+                }
+                let user_group_id = UserGroupId{user_id, group_id};
+                Ok(if !guard.join_requests.contains_key(&user_group_id)
+                {
+                    response_error("no such join request")
+                }
+                else if !has_permission(&guard, admin_id, group_id, Permission::Approve)
+                {
+                    response_missing_permission(Permission::Approve)
+                }
+                else
+                {
+                    guard.join_requests.remove(&user_group_id);
+                    response_empty()
+                })
+            });
+        metrics.route(&mut app, "/group/invite/create")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let group_id: Id = get_field(object, "group_id");
+                let admin_id = auth::acting_user(&request);
+                let expires_at = match object.get("expires_at")
+                {
+                    None | Some(Value::Null) => None,
+                    Some(value) => match value.as_str().and_then(|x| x.parse::<DateTime<Utc>>().ok())
+                    {
+                        None => return Ok(response_error("wrong format expires_at")),
+                        Some(expires_at) => Some(expires_at),
+                    },
+                };
+                let max_uses = match object.get("max_uses")
+                {
+                    None | Some(Value::Null) => None,
+                    Some(value) => match value.as_u64().and_then(|x| u32::try_from(x).ok()).filter(|&x| x > 0)
+                    {
+                        None => return Ok(response_error("wrong format max_uses")),
+                        Some(max_uses) => Some(max_uses),
+                    },
+                };
+
+                let mut guard = request.state().lock().unwrap();
+                if is_archived(&guard, auth::acting_user(&request), group_id)
+                {
+                    return Ok(response_archived());
+                }
+                Ok(match guard.groups.get(&group_id)
+                {
+                    None => response_error("no such group"),
+                    Some(_) if !is_group_visible(&guard, admin_id, group_id) => response_error("no such group"),
+                    Some(group) if group.is_closed => response_error("group is closed"),
+                    Some(_) if !has_permission(&guard, admin_id, group_id, Permission::Invite) => response_missing_permission(Permission::Invite),
+                    Some(_) =>
+                    {
+                        let code = auth::random_hex(6);
+                        guard.invites.insert(code.clone(), Invite { group_id, created_by: admin_id, expires_at, max_uses, uses: 0 });
+                        response_data(json!({"code": code}))
+                    },
+                })
+            });
+        metrics.route(&mut app, "/group/invite/list/:group_id")
+            .with(auth::Authentication)
+            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
+                let raw_id = request.param("group_id")?;
+                let admin_id = auth::acting_user(&request);
+                let guard = request.state().lock().unwrap();
+                Ok(match raw_id.parse().ok()
+                {
+                    None => response_error("wrong format group_id"),
+                    Some(group_id) =>
+                    {
+                        if !is_group_visible(&guard, admin_id, group_id)
+                        {
+                            response_error("no such group")
+                        }
+                        else if !has_permission(&guard, admin_id, group_id, Permission::Invite)
+                        {
+                            response_missing_permission(Permission::Invite)
+                        }
+                        else
+                        {
+                            let mut invites: Vec<Value> = guard.invites.iter()
+                                .filter(|&(_, invite)| invite.group_id == group_id)
+                                .map(|(code, invite)|
+                                {
+                                    let mut item = json!(invite);
+                                    item["code"] = json!(code);
+                                    item
+                                })
+                                .collect();
+                            invites.sort_by(|a, b| a["code"].as_str().cmp(&b["code"].as_str()));
+                            response_data(json!(invites))
+                        }
+                    },
+                })
+            });
+        metrics.route(&mut app, "/group/invite/revoke")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let code = object.get("code").and_then(|x| x.as_str()).unwrap_or_default().to_string();
+                let admin_id = auth::acting_user(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                Ok(match guard.invites.get(&code)
+                {
+                    None => response_error("no such invite"),
+                    Some(invite) if !has_permission(&guard, admin_id, invite.group_id, Permission::Invite) => response_missing_permission(Permission::Invite),
+                    Some(_) =>
+                    {
+                        guard.invites.remove(&code);
+                        response_empty()
+                    },
+                })
+            });
+        metrics.route(&mut app, "/group/join_by_code")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let code = object.get("code").and_then(|x| x.as_str()).unwrap_or_default().to_string();
+                let user_id = auth::acting_user(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                let group_id = match guard.invites.get(&code)
+                {
+                    None => return Ok(response_error("no such invite")),
+                    Some(invite) => match invite.check()
+                    {
+                        Err(msg) => return Ok(response_error(msg)),
+                        Ok(()) => invite.group_id,
+                    },
+                };
+                Ok(match join_group(&mut guard, user_id, group_id)
+                {
+                    Ok(()) =>
+                    {
+                        guard.invites.get_mut(&code).unwrap().uses += 1;
+                        response_data(json!({"group_id": group_id}))
+                    },
+                    Err(msg) => response_error(msg),
+                })
+            });
+        metrics.route(&mut app, "/group/join_many")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let group_id: Id = get_field(object, "group_id");
+                let user_ids = object.get("user_ids").and_then(|x| x.as_array()).cloned().unwrap_or_default();
+                let partial = object.get("partial").and_then(|x| x.as_bool()).unwrap_or(false);
+                let admin_id = auth::acting_user(&request);
+
+                // Права проверяются под той же блокировкой, что и вступление, иначе администратор,
+                // лишенный прав между проверкой и вступлением, все равно добавил бы участников.
+                let check = |db: &DataBase| if !is_group_visible(db, admin_id, group_id)
+                {
+                    Some(response_error("no such group"))
+                }
+                else if !has_permission(db, admin_id, group_id, Permission::Invite)
+                {
+                    Some(response_missing_permission(Permission::Invite))
+                }
+                else
+                {
+                    None
+                };
+                Ok(run_bulk(request.state(), &user_ids, partial, check, |db, item|
+                {
+                    let user_id = parse_id(item).ok_or("wrong format user_id")?;
+                    join_group(db, user_id, group_id)?;
+                    Ok(json!({"user_id": user_id}))
+                }))
+            });
+        metrics.route(&mut app, "/group/unadmin")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let admin_id = auth::acting_user(&request);
+                let group_id = get_field(object, "group_id");
+                let if_match = if_match(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                if is_archived(&guard, auth::acting_user(&request), group_id)
+                {
+                    return Ok(response_archived());
+                }
+                let user_group_id = UserGroupId{user_id: admin_id, group_id};
+                Ok(match guard.user_groups.get(&user_group_id)
+                {
+                    None => response_error("user does not belong to this group"),
+                    Some(user_group_props) =>
+                    {
+                        if !revision_matches(&if_match, user_group_props.revision)
+                        {
+                            response_precondition_failed(user_group_props.revision)
+                        }
+                        else if user_group_props.access_level == Access::Owner
+                        {
+                            response_error("owner cannot step down, transfer ownership first")
+                        }
+                        else if user_group_props.access_level != Access::Admin
+                        {
+                            response_error("This user is not an admin.")
+                        }
+                        else
+                        {
+                            if count_admins(group_id, &guard.user_groups) < 2
+                            {
+                                response_error("It is impossible to remove the last admin in a group. You can appoint a new admin and repeat or delete the whole group.")
+                            }
+                            else
+                            {
+                                let user_group_props = guard.user_groups.get_mut(&user_group_id).unwrap();
+                                user_group_props.access_level = Access::User;
+                                user_group_props.revision += 1;
+                                with_etag(response_empty(), user_group_props.revision)
+                            }
+                        }
+                    }
+                })
+            });
+        metrics.route(&mut app, "/group/delete")
+            .with(auth::Authentication)
+            .delete(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let admin_id = auth::acting_user(&request);
+                let group_id = get_field(object, "group_id");
+                let if_match = if_match(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                Ok(match guard.user_groups.get(&UserGroupId{user_id: admin_id, group_id})
+                {
+                    None => response_error("user does not belong to this group"),
+                    Some(_) =>
+                    {
+                        let revision = guard.groups[&group_id].revision;
+                        if !has_permission(&guard, admin_id, group_id, Permission::Delete)
+                        {
+                            response_missing_permission(Permission::Delete)
+                        }
+                        else if !revision_matches(&if_match, revision)
+                        {
+                            response_precondition_failed(revision)
+                        }
+                        else
+                        {
+                            // Before delete group, we need to delete all users from this group
+                            guard.user_groups.retain(|user_group_id, _|
+                                {
+                                    user_group_id.group_id != group_id
+                                });
+                            guard.groups.remove(&group_id);
+                            guard.invites.retain(|_, invite| invite.group_id != group_id);
+                            guard.join_requests.retain(|key, _| key.group_id != group_id);
+                            guard.bans.retain(|key| key.group_id != group_id);
+                            response_empty()
+                        }
+                    }
+                }
+            )});
+        metrics.route(&mut app, "/group/update")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let group_id: Id = get_field(object, "group_id");
+                let admin_id = auth::acting_user(&request);
+                // Отсутствующее поле не меняется, null очищает значение.
+                let budget = match object.get("budget")
+                {
+                    None => None,
+                    Some(Value::Null) => Some(None),
+                    Some(value) => match value.as_u64().and_then(|x| x.try_into().ok())
+                    {
+                        None => return Ok(response_error("wrong format budget")),
+                        Some(budget) => Some(Some(budget)),
+                    },
+                };
+                let exchange_date = match object.get("exchange_date")
+                {
+                    None => None,
+                    Some(Value::Null) => Some(None),
+                    Some(value) => match value.as_str().and_then(|x| x.parse().ok())
+                    {
+                        None => return Ok(response_error("wrong format exchange_date")),
+                        Some(date) => Some(Some(date)),
+                    },
+                };
+                let approval_required = match object.get("approval_required")
+                {
+                    None => None,
+                    Some(value) => match value.as_bool()
+                    {
+                        None => return Ok(response_error("wrong format approval_required")),
+                        Some(approval_required) => Some(approval_required),
+                    },
+                };
+                let mut times = Vec::new();
+                for key in ["join_deadline", "draw_at"]
+                {
+                    times.push(match object.get(key)
+                    {
+                        None => None,
+                        Some(Value::Null) => Some(None),
+                        Some(value) => match value.as_str().and_then(|x| x.parse::<DateTime<Utc>>().ok())
+                        {
+                            None => return Ok(response_error(&format!("wrong format {}", key))),
+                            Some(time) if time <= Utc::now() => return Ok(response_error(&format!("{} must be in the future", key))),
+                            Some(time) => Some(Some(time)),
+                        },
+                    });
+                }
+                let (join_deadline, draw_at) = (times[0], times[1]);
+                let if_match = if_match(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                if is_archived(&guard, auth::acting_user(&request), group_id)
+                {
+                    return Ok(response_archived());
+                }
+                Ok(if !is_group_visible(&guard, admin_id, group_id)
+                {
+                    response_error("no such group")
+                }
+                else if !has_permission(&guard, admin_id, group_id, Permission::EditMetadata)
+                {
+                    response_missing_permission(Permission::EditMetadata)
+                }
+                else if !revision_matches(&if_match, guard.groups[&group_id].revision)
+                {
+                    response_precondition_failed(guard.groups[&group_id].revision)
+                }
+                else if let Some(Err((status, msg))) = exchange_date.map(|date| {
+                    check_exchange_date(&guard.groups[&group_id], is_drawn(&guard, group_id), date, Utc::now().date_naive())
+                })
+                {
+                    response_error_with_status(status, msg)
+                }
+                // Запланировать жеребьевку - то же, что провести ее.
+                else if matches!(draw_at, Some(Some(_))) && !has_permission(&guard, admin_id, group_id, Permission::Draw)
+                {
+                    response_missing_permission(Permission::Draw)
+                }
+                else if matches!(draw_at, Some(Some(_))) && is_drawn(&guard, group_id)
+                {
+                    response_error_with_status(409, "already drawn")
+                }
+                else if matches!(
+                    (join_deadline.unwrap_or(guard.groups[&group_id].join_deadline), draw_at.unwrap_or(guard.groups[&group_id].draw_at)),
+                    (Some(deadline), Some(draw_at)) if deadline > draw_at
+                )
+                {
+                    response_error("join_deadline must not be after draw_at")
+                }
+                else
+                {
+                    let group = guard.groups.get_mut(&group_id).unwrap();
+                    if let Some(budget) = budget
+                    {
+                        group.budget = budget;
+                    }
+                    if let Some(exchange_date) = exchange_date
+                    {
+                        group.exchange_date = exchange_date;
+                    }
+                    if let Some(approval_required) = approval_required
+                    {
+                        group.approval_required = approval_required;
+                    }
+                    if let Some(join_deadline) = join_deadline
+                    {
+                        group.join_deadline = join_deadline;
+                    }
+                    if let Some(draw_at) = draw_at
+                    {
+                        group.draw_at = draw_at;
+                        group.scheduled_draw_error = None;
+                    }
+                    group.revision += 1;
+                    with_etag(response_empty(), group.revision)
+                })
+            });
+        metrics.route(&mut app, "/group/permissions")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let group_id: Id = get_field(object, "group_id");
+                let role = object.get("role").and_then(|x| x.as_str()).unwrap_or_default().to_string();
+                let permissions: HashSet<Permission> = match object.get("permissions").map(|x| serde_json::from_value(x.clone()))
+                {
+                    Some(Ok(permissions)) => permissions,
+                    _ => return Ok(response_error("wrong format permissions")),
+                };
+                let owner_id = auth::acting_user(&request);
+                let if_match = if_match(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                if is_archived(&guard, auth::acting_user(&request), group_id)
+                {
+                    return Ok(response_archived());
+                }
+                let is_owner = guard.user_groups.get(&UserGroupId{user_id: owner_id, group_id})
+                    .is_some_and(|props| props.access_level == Access::Owner);
+                let is_visible = is_group_visible(&guard, owner_id, group_id);
+                Ok(match guard.groups.get_mut(&group_id)
+                {
+                    None => response_error("no such group"),
+                    Some(_) if !is_visible => response_error("no such group"),
+                    Some(_) if !is_owner => response_error_with_status(403, "only the owner can change permissions"),
+                    Some(group) if !revision_matches(&if_match, group.revision) => response_precondition_failed(group.revision),
+                    Some(group) =>
+                    {
+                        match role.as_str()
+                        {
+                            "admin" => group.admin_permissions = permissions,
+                            "user" => group.user_permissions = permissions,
+                            _ => return Ok(response_error("wrong role")),
+                        }
+                        group.revision += 1;
+                        with_etag(response_empty(), group.revision)
+                    },
+                })
+            });
+        metrics.route(&mut app, "/group/transfer_ownership")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let group_id: Id = get_field(object, "group_id");
+                let new_owner_id: Id = get_field(object, "new_owner_id");
+                let owner_id = auth::acting_user(&request);
+                let if_match = if_match(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                if is_archived(&guard, auth::acting_user(&request), group_id)
+                {
+                    return Ok(response_archived());
+                }
+                Ok(match guard.groups.get(&group_id)
+                {
+                    None => response_error("no such group"),
+                    Some(_) if !is_group_visible(&guard, owner_id, group_id) => response_error("no such group"),
+                    Some(_) if guard.user_groups.get(&UserGroupId{user_id: owner_id, group_id}).map(|props| &props.access_level) != Some(&Access::Owner) =>
+                    {
+                        response_error_with_status(403, "only the owner can transfer ownership")
+                    },
+                    Some(group) if !revision_matches(&if_match, group.revision) => response_precondition_failed(group.revision),
+                    Some(_) => match transfer_ownership(&mut guard, group_id, new_owner_id)
+                    {
+                        Ok(revision) => with_etag(response_empty(), revision),
+                        Err(msg) => response_error(msg),
+                    },
+                })
+            });
+        metrics.route(&mut app, "/operator/transfer_ownership")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let group_id: Id = get_field(object, "group_id");
+                let new_owner_id: Id = get_field(object, "new_owner_id");
+                let operator_id = auth::acting_user(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                Ok(if !guard.users.get(&operator_id).is_some_and(|user| user.is_operator)
+                {
+                    response_error_with_status(403, "operator only")
+                }
+                // Оператор работает с группами всех организаций, is_archived видит только группы его собственной.
+                else if guard.groups.get(&group_id).is_some_and(|group| group.archived_at.is_some())
+                {
+                    response_archived()
+                }
+                else
+                {
+                    match transfer_ownership(&mut guard, group_id, new_owner_id)
+                    {
+                        Ok(revision) => with_etag(response_empty(), revision),
+                        Err(msg) => response_error(msg),
+                    }
+                })
+            });
+        metrics.route(&mut app, "/operator/organization/create")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let name: String = get_field(object, "name");
+                let admin_name: String = get_field(object, "admin_name");
+                let operator_id = auth::acting_user(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                Ok(if !guard.users.get(&operator_id).is_some_and(|user| user.is_operator)
+                {
+                    response_error_with_status(403, "operator only")
+                }
+                else
+                {
+                    match create_organization(&mut guard, name, admin_name)
+                    {
+                        Ok((organization_id, admin_id, token)) => response_data(json!({
+                            "organization_id": organization_id,
+                            "admin": {"id": admin_id, "token": token},
+                        })),
+                        Err(msg) => response_error(msg),
+                    }
+                })
+            });
+        metrics.route(&mut app, "/operator/organizations")
+            .with(auth::Authentication)
+            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
+                let operator_id = auth::acting_user(&request);
+                let guard = request.state().lock().unwrap();
+                Ok(if !guard.users.get(&operator_id).is_some_and(|user| user.is_operator)
+                {
+                    response_error_with_status(403, "operator only")
+                }
+                else
+                {
+                    let mut ids: Vec<Id> = guard.organizations.keys().copied().collect();
+                    ids.sort();
+                    let items: Vec<Value> = ids.into_iter().map(|id| organization_info(&guard, id)).collect();
+                    response_data(json!({"items": items}))
+                })
+            });
+        metrics.route(&mut app, "/organization")
+            .with(auth::Authentication)
+            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
+                let guard = request.state().lock().unwrap();
+                Ok(match organization_of(&guard, auth::acting_user(&request))
+                {
+                    None => response_error("no such user"),
+                    Some(organization_id) => with_etag(
+                        response_data(organization_info(&guard, organization_id)),
+                        guard.organizations[&organization_id].revision,
+                    ),
+                })
             });
-        app.at("/group/create")
+        metrics.route(&mut app, "/organization/update")
+            .with(auth::Authentication)
             .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
                 let body: Value = request.body_json().await?;
                 let object = body.as_object().unwrap();
-                let creator_id: Id = get_field(object, "creator_id");
+                let name: String = get_field(object, "name");
+                let admin_id = auth::acting_user(&request);
+                let if_match = if_match(&request);
 
                 let mut guard = request.state().lock().unwrap();
-                Ok(if !guard.users.contains_key(&creator_id)
+                if !is_org_admin(&guard, admin_id)
+                {
+                    return Ok(response_org_admin_only());
+                }
+                let organization_id = guard.users[&admin_id].organization_id;
+                let organization = guard.organizations.get_mut(&organization_id).unwrap();
+                Ok(if !revision_matches(&if_match, organization.revision)
+                {
+                    response_precondition_failed(organization.revision)
+                }
+                else if name.is_empty()
                 {
-                    response_error("no such user")
+                    response_error("bad name")
                 }
                 else
                 {
-                    let id = guard.groups_max_id;
-                    guard.groups.insert(id, false);
-                    guard.groups_max_id += 1;
-                    guard.user_groups.insert(
-                        UserGroupId
-                        {
-                            user_id: creator_id,
-                            group_id: id,
-                        },
-                        UserGroupProps::new(Access::Admin)
-                    );
-                    response_data(json!({"group_id": id}))
+                    organization.name = name;
+                    organization.revision += 1;
+                    with_etag(response_empty(), organization.revision)
                 })
             });
-        app.at("/group/join")
+        metrics.route(&mut app, "/organization/user/create")
+            .with(auth::Authentication)
             .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
-                let value: Value = request.body_json().await.unwrap();
-                let object = value.as_object().unwrap();
-                let user_id = get_field(object, "user_id");
-                let group_id = get_field(object, "group_id");
+                let body: Value = request.body_json().await?;
+                let input_obj = body.as_object().unwrap();
+                let admin_id = auth::acting_user(&request);
 
-                let mut guard = request.state().lock().unwrap();
-                Ok(match guard.groups.get(&group_id)
-                {
-                    None => response_error("no such group"),
-                    Some(is_closed) =>
+                let organization_id = {
+                    let guard = request.state().lock().unwrap();
+                    if !is_org_admin(&guard, admin_id)
                     {
-                        if *is_closed
-                        {
-                            response_error("group is closed")
-                        }
-                        else
-                        {
-                            if !guard.users.contains_key(&user_id)
-                            {
-                                response_error("no such user")
-                            }
-                            else
-                            {
-                                let user_group_id = UserGroupId{user_id, group_id};
-                                if guard.user_groups.contains_key(&user_group_id)
-                                {
-                                    response_error("user already in group")
-                                }
-                                else
-                                {
-                                    guard.user_groups.insert(user_group_id, UserGroupProps::new(Access::User));
-                                    response_empty()
-                                }
-                            }
-                        }
-                    },
-                })
+                        return Ok(response_org_admin_only());
+                    }
+                    guard.users[&admin_id].organization_id
+                };
+                Ok(user_create(input_obj, request.state(), organization_id))
             });
-        app.at("/group/unadmin")
+        metrics.route(&mut app, "/organization/user/create_many")
+            .with(auth::Authentication)
             .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
                 let body: Value = request.body_json().await?;
                 let object = body.as_object().unwrap();
-                let admin_id = get_field(object, "admin_id");
-                let group_id = get_field(object, "group_id");
+                let admin_id = auth::acting_user(&request);
 
-                let mut guard = request.state().lock().unwrap();
-                let user_group_id = UserGroupId{user_id: admin_id, group_id};
-                Ok(match guard.user_groups.get(&user_group_id)
-                {
-                    None => response_error("user does not belong to this group"),
-                    Some(user_group_props) =>
+                let organization_id = {
+                    let guard = request.state().lock().unwrap();
+                    if !is_org_admin(&guard, admin_id)
                     {
-                        if user_group_props.access_level != Access::Admin
-                        {
-                            response_error("This user is not an admin.")
-                        }
-                        else
-                        {
-                            if count_admins(group_id, &guard.user_groups) < 2
-                            {
-                                response_error("It is impossible to remove the last admin in a group. You can appoint a new admin and repeat or delete the whole group.")
-                            }
-                            else
-                            {
-                                guard.user_groups.get_mut(&user_group_id).unwrap().access_level = Access::User;
-                                response_empty()
-                            }
-                        }
+                        return Ok(response_org_admin_only());
                     }
-                })
+                    guard.users[&admin_id].organization_id
+                };
+                Ok(users_create_many(object, request.state(), organization_id))
             });
-        app.at("/group/delete")
-            .delete(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+        metrics.route(&mut app, "/organization/make_admin")
+            .with(auth::Authentication)
+            .post(|request: Request<Arc<Mutex<DataBase>>>| set_org_admin(request, true));
+        metrics.route(&mut app, "/organization/unadmin")
+            .with(auth::Authentication)
+            .post(|request: Request<Arc<Mutex<DataBase>>>| set_org_admin(request, false));
+        metrics.route(&mut app, "/group/wishlist")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
                 let body: Value = request.body_json().await?;
                 let object = body.as_object().unwrap();
-                let admin_id = get_field(object, "admin_id");
-                let group_id = get_field(object, "group_id");
+                let group_id: Id = get_field(object, "group_id");
+                let user_id = auth::acting_user(&request);
+                let wishlist = match object.get("wishlist").and_then(|x| x.as_str())
+                {
+                    None => return Ok(response_error("wrong format wishlist")),
+                    Some(wishlist) => wishlist.to_string(),
+                };
+                let if_match = if_match(&request);
 
                 let mut guard = request.state().lock().unwrap();
-                Ok(match guard.user_groups.get(&UserGroupId{user_id: admin_id, group_id: group_id})
+                if is_archived(&guard, auth::acting_user(&request), group_id)
+                {
+                    return Ok(response_archived());
+                }
+                Ok(match guard.user_groups.get_mut(&UserGroupId{user_id, group_id})
                 {
                     None => response_error("user does not belong to this group"),
                     Some(user_group_props) =>
                     {
-                        if user_group_props.access_level != Access::Admin
+                        if !revision_matches(&if_match, user_group_props.revision)
                         {
-                            response_error("This user is not an admin.")
+                            response_precondition_failed(user_group_props.revision)
                         }
                         else
                         {
-                            // Before delete group, we need to delete all users from this group
-                            guard.user_groups.retain(|user_group_id, _|
-                                {
-                                    user_group_id.group_id != group_id
-                                });
-                            guard.groups.remove(&group_id);
-                            response_empty()
+                            user_group_props.wishlist = wishlist;
+                            user_group_props.revision += 1;
+                            with_etag(response_empty(), user_group_props.revision)
                         }
                     }
-                }
-            )});
-        app.at("/group/make_admin")
+                })
+            });
+        metrics.route(&mut app, "/group/make_admin")
+            .with(auth::Authentication)
             .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
                 let body: Value = request.body_json().await?;
                 let object = body.as_object().unwrap();
                 let group_id: Id = get_field(object, "group_id");
                 let member_id: Id = get_field(object, "member_id");
-                let admin_id: Id = get_field(object, "admin_id");
+                let admin_id = auth::acting_user(&request);
+                let if_match = if_match(&request);
 
                 let mut guard = request.state().lock().unwrap();
-                Ok(if !guard.groups.contains_key(&group_id)
+                if is_archived(&guard, auth::acting_user(&request), group_id)
+                {
+                    return Ok(response_archived());
+                }
+                let member_group_id = UserGroupId{user_id: member_id, group_id};
+                Ok(if !is_group_visible(&guard, admin_id, group_id)
                 {
                     response_error("no such group")
                 }
@@ -313,33 +2196,113 @@ fn main() -> Result<(), std::io::Error>
                 {
                     response_error("admin_id isn't an actual admin's ID")
                 }
+                else if !revision_matches(&if_match, guard.user_groups[&member_group_id].revision)
+                {
+                    response_precondition_failed(guard.user_groups[&member_group_id].revision)
+                }
                 else {
-                    guard.user_groups.insert(
-                        UserGroupId {
-                            user_id: member_id,
-                            group_id,
-                        },
-                        UserGroupProps::new(Access::Admin),
-                    );
-                    response_empty()
+                    let user_group_props = guard.user_groups.get_mut(&member_group_id).unwrap();
+                    user_group_props.access_level = Access::Admin;
+                    user_group_props.revision += 1;
+                    with_etag(response_empty(), user_group_props.revision)
                 }
             )});
-        app.at("/group/list_admins/:group_id")
+        metrics.route(&mut app, "/user/info/:user_id")
+            .with(auth::Authentication)
+            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
+                let raw_id = request.param("user_id")?;
+                let guard = request.state().lock().unwrap();
+                let organization_id = organization_of(&guard, auth::acting_user(&request));
+                Ok(match raw_id.parse().ok()
+                {
+                    None => response_error("wrong format user_id"),
+                    Some(user_id) => match guard.users.get(&user_id)
+                    {
+                        Some(user) if Some(user.organization_id) == organization_id => with_etag(
+                            response_data(json!({
+                                "id": user_id,
+                                "name": user.name,
+                                "revision": user.revision,
+                                "organization_id": user.organization_id,
+                                "is_org_admin": user.is_org_admin,
+                            })),
+                            user.revision,
+                        ),
+                        _ => response_error("no such user"),
+                    },
+                })
+            });
+        metrics.route(&mut app, "/group/info/:group_id")
+            .with(auth::Authentication)
+            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
+                let raw_id = request.param("group_id")?;
+                let user_id = auth::acting_user(&request);
+                let guard = request.state().lock().unwrap();
+                Ok(match raw_id.parse().ok()
+                {
+                    None => response_error("wrong format group_id"),
+                    Some(group_id) => match guard.groups.get(&group_id)
+                    {
+                        None => response_error("no such group"),
+                        Some(_) if !is_group_visible(&guard, user_id, group_id) => response_error("no such group"),
+                        Some(group) =>
+                        {
+                            let mut members: Vec<Value> = guard.user_groups.iter()
+                                .filter(|&(key, _)| key.group_id == group_id)
+                                .map(|(key, props)| json!({
+                                    "user_id": key.user_id,
+                                    "access_level": props.access_level,
+                                    "revision": props.revision,
+                                }))
+                                .collect();
+                            members.sort_by_key(|member| member["user_id"].as_u64());
+                            with_etag(
+                                response_data(json!({
+                                    "id": group_id,
+                                    "is_closed": group.is_closed,
+                                    "phase": group_phase(&guard, group_id),
+                                    "revision": group.revision,
+                                    "budget": group.budget,
+                                    "exchange_date": group.exchange_date,
+                                    "join_deadline": group.join_deadline,
+                                    "draw_at": group.draw_at,
+                                    "scheduled_draw_error": group.scheduled_draw_error,
+                                    "archived_at": group.archived_at,
+                                    "purged_at": group.purged_at,
+                                    "stats": group.stats,
+                                    "approval_required": group.approval_required,
+                                    "commitment": group.commitment.as_ref().map(|commitment| &commitment.hash),
+                                    "permissions": {
+                                        "admin": sorted_permissions(&group.admin_permissions),
+                                        "user": sorted_permissions(&group.user_permissions),
+                                    },
+                                    "members": members,
+                                })),
+                                group.revision,
+                            )
+                        },
+                    },
+                })
+            });
+        metrics.route(&mut app, "/group/list_admins/:group_id")
+            .with(auth::Authentication)
             .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
                 let raw_id = request.param("group_id")?;
+                let user_id = auth::acting_user(&request);
                 let guard = request.state().lock().unwrap();
                 Ok(match raw_id.parse().ok()
                 {
                     None => response_error("wrong format group_id"),
                     Some(group_id) =>
                     {
-                        if !guard.groups.contains_key(&group_id)
+                        if !is_group_visible(&guard, user_id, group_id)
                         {
                             response_error("no such group")
                         }
                         else
                         {
                             let admins: HashMap<&Id, &String> = guard.users.iter()
+                            .map(|(id, user)| (id, &user.name))
                             .filter(|&x| match guard.user_groups.get(
                                 &UserGroupId {
                                     user_id: *x.0,
@@ -347,7 +2310,7 @@ fn main() -> Result<(), std::io::Error>
                                 })
                             {
                                 None => false,
-                                Some(user_group_props) => user_group_props.access_level == Access::Admin
+                                Some(user_group_props) => user_group_props.access_level.is_admin()
                             })
                             .collect();
                             response_data(json!(admins))
@@ -355,12 +2318,14 @@ fn main() -> Result<(), std::io::Error>
                     },
                 })
             });
-        app.at("/group/quit")
+        metrics.route(&mut app, "/group/quit")
+            .with(auth::Authentication)
             .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
                 let body: Value = request.body_json().await?;
                 let object = body.as_object().unwrap();
                 let group_id: Id = get_field(object, "group_id");
-                let user_id: Id = get_field(object, "user_id");
+                let user_id = auth::acting_user(&request);
+                let if_match = if_match(&request);
 
                 let mut guard = request.state().lock().unwrap();
                 let user_group_id = UserGroupId{user_id, group_id};
@@ -369,26 +2334,88 @@ fn main() -> Result<(), std::io::Error>
                     None => response_error("user does not belong to this group"),
                     Some(user_group_props) =>
                     {
-                        if user_group_props.access_level == Access::Admin && count_admins(group_id, &guard.user_groups) < 2
+                        if !revision_matches(&if_match, user_group_props.revision)
                         {
-                            response_error("user is only one Admin in this group")
+                            response_precondition_failed(user_group_props.revision)
                         }
                         else
                         {
-                            if *guard.groups.get(&group_id).unwrap()
-                            {
-                                response_error("group is closed")
-                            }
-                            else
+                            match leave_group(&mut guard, &user_group_id)
                             {
-                                guard.user_groups.remove(&user_group_id);
-                                response_empty()
+                                Ok(()) => response_empty(),
+                                Err(msg) => response_error(msg),
                             }
                         }
                     }
                 })
             });
-        app.at("/group/target_by_id/:user_id/:group_id")
+        metrics.route(&mut app, "/group/kick")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let group_id: Id = get_field(object, "group_id");
+                let user_id: Id = get_field(object, "user_id");
+                let ban = object.get("ban").and_then(|x| x.as_bool()).unwrap_or(false);
+                let admin_id = auth::acting_user(&request);
+                let if_match = if_match(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                let user_group_id = UserGroupId{user_id, group_id};
+                Ok(match guard.user_groups.get(&user_group_id)
+                {
+                    _ if !is_group_visible(&guard, admin_id, group_id) => response_error("no such group"),
+                    None => response_error("user does not belong to this group"),
+                    Some(_) if !has_permission(&guard, admin_id, group_id, Permission::Kick) => response_missing_permission(Permission::Kick),
+                    Some(user_group_props) if !revision_matches(&if_match, user_group_props.revision) => response_precondition_failed(user_group_props.revision),
+                    Some(_) => match leave_group(&mut guard, &user_group_id)
+                    {
+                        Err(msg) => response_error(msg),
+                        Ok(()) =>
+                        {
+                            if ban
+                            {
+                                guard.bans.insert(user_group_id);
+                            }
+                            response_empty()
+                        },
+                    },
+                })
+            });
+        metrics.route(&mut app, "/group/unban")
+            .with(auth::Authentication)
+            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
+                let body: Value = request.body_json().await?;
+                let object = body.as_object().unwrap();
+                let group_id: Id = get_field(object, "group_id");
+                let user_id: Id = get_field(object, "user_id");
+                let admin_id = auth::acting_user(&request);
+
+                let mut guard = request.state().lock().unwrap();
+                if is_archived(&guard, auth::acting_user(&request), group_id)
+                {
+                    return Ok(response_archived());
+                }
+                let user_group_id = UserGroupId{user_id, group_id};
+                Ok(if !is_group_visible(&guard, admin_id, group_id)
+                {
+                    response_error("no such group")
+                }
+                else if !has_permission(&guard, admin_id, group_id, Permission::Kick)
+                {
+                    response_missing_permission(Permission::Kick)
+                }
+                else if !guard.bans.remove(&user_group_id)
+                {
+                    response_error("user is not banned")
+                }
+                else
+                {
+                    response_empty()
+                })
+            });
+        metrics.route(&mut app, "/group/target_by_id/:user_id/:group_id")
+            .with(auth::Authentication)
             .get(|request: Request<Arc<Mutex<DataBase>>>| async move{
                 let first_id = request.param("user_id")?;
                 let second_id = request.param("group_id")?;
@@ -404,104 +2431,182 @@ fn main() -> Result<(), std::io::Error>
                 }
                 let user_id: Id = first_id.parse().unwrap(); // TODO
                 let group_id: Id = second_id.parse().unwrap();
+                if user_id != auth::acting_user(&request)
+                {
+                    return Ok(response_error_with_status(403, "target is visible only to the santa"));
+                }
 
                 let guard = request.state().lock().unwrap();
                 Ok(match guard.user_groups.get(&UserGroupId{user_id, group_id})
                 {
                     None => response_error("user does not belong to this group"),
-                    Some(user_group_props) =>
+                    Some(user_group_props) => match user_group_props.santa_id
                     {
-                        response_data(json!({"cysh_for_id": user_group_props.santa_id}))
+                        None => response_error_with_status(409, "not drawn yet"),
+                        Some(recipient_id) =>
+                        {
+                            let group = &guard.groups[&group_id];
+                            let wishlist = &guard.user_groups[&UserGroupId{user_id: recipient_id, group_id}].wishlist;
+                            response_data(json!({
+                                "recipient": {
+                                    "id": recipient_id,
+                                    "name": guard.users.get(&recipient_id).map(|user| &user.name),
+                                    "wishlist": wishlist,
+                                },
+                                "budget": group.budget,
+                                "exchange_date": group.exchange_date,
+                            }))
+                        }
                     }
                 })
             });
-        app.at("/group/secret_santa")
+        metrics.route(&mut app, "/group/assignments/:group_id")
+            .with(auth::Authentication)
+            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
+                let raw_id = request.param("group_id")?;
+                let user_id = auth::acting_user(&request);
+                let guard = request.state().lock().unwrap();
+                let group_id: Id = match raw_id.parse()
+                {
+                    Ok(group_id) => group_id,
+                    Err(_) => return Ok(response_error("wrong format group_id")),
+                };
+                Ok(match guard.groups.get(&group_id)
+                {
+                    None => response_error("no such group"),
+                    Some(_) if !is_group_visible(&guard, user_id, group_id) => response_error("no such group"),
+                    Some(_) if !does_user_belong_to_group(user_id, group_id, &guard.user_groups) =>
+                    {
+                        response_error("user does not belong to this group")
+                    },
+                    Some(_) if !is_drawn(&guard, group_id) => response_error_with_status(409, "not drawn yet"),
+                    Some(group) if !is_revealed(group) =>
+                    {
+                        response_error_with_status(403, "assignments are hidden until after the exchange date")
+                    },
+                    Some(group) =>
+                    {
+                        let pairs: Vec<Value> = assignments(&guard, group_id).into_iter()
+                            .map(|(santa_id, recipient_id)| json!({"santa_id": santa_id, "recipient_id": recipient_id}))
+                            .collect();
+                        response_data(json!({
+                            "assignments": pairs,
+                            "commitment": group.commitment.as_ref().map(|commitment| &commitment.hash),
+                            "salt": group.commitment.as_ref().map(|commitment| &commitment.salt),
+                        }))
+                    },
+                })
+            });
+        metrics.route(&mut app, "/group/secret_santa")
+            .with(auth::Authentication)
             .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
                 let body: Value = request.body_json().await?;
                 let object = body.as_object().unwrap();
                 let group_id: Id = get_field(object, "group_id");
-                let admin_id: Id = get_field(object, "admin_id");
+                let admin_id = auth::acting_user(&request);
+                let if_match = if_match(&request);
 
                 let mut guard = request.state().lock().unwrap();
+                if is_archived(&guard, auth::acting_user(&request), group_id)
+                {
+                    return Ok(response_archived());
+                }
                 Ok(match guard.user_groups.get(&UserGroupId{user_id: admin_id, group_id})
                 {
                     None => response_error("user does not belong to this group"),
-                    Some(user_group_props) =>
+                    Some(_) =>
                     {
-                        if user_group_props.access_level != Access::Admin
+                        let revision = guard.groups[&group_id].revision;
+                        if !has_permission(&guard, admin_id, group_id, Permission::Draw)
+                        {
+                            response_missing_permission(Permission::Draw)
+                        }
+                        else if !revision_matches(&if_match, revision)
+                        {
+                            response_precondition_failed(revision)
+                        }
+                        // Повторная жеребьевка позволила бы перебирать пары до нужных.
+                        else if is_drawn(&guard, group_id)
+                        {
+                            response_error_with_status(409, "already drawn")
+                        }
+                        else if let Some(problem) = draw_problem(&guard, group_id)
                         {
-                            response_error("its not admin")
+                            let mut response = response_error_with_status(409, problem);
+                            response.insert_ext(metrics::DrawAttempt(false));
+                            response
                         }
                         else
                         {
-                            *guard.groups.get_mut(&(group_id)).unwrap() = true;
-                            let group: Vec<Id> = guard.user_groups.keys().filter_map(|key|
-                                match key.group_id == group_id
-                                {
-                                    true => Some(key.user_id),
-                                    false => None,
-                                }
-                            ).collect();
-                            let santas = get_secret_santas(&group);
-                            for i in 0..group.len()
-                            {
-                                guard.user_groups.get_mut(&UserGroupId{user_id: group[i], group_id}).unwrap().santa_id = santas[i];
-                            }
-                            response_empty()
+                            let revision = draw(&mut guard, group_id);
+                            let mut response = with_etag(response_empty(), revision);
+                            response.insert_ext(metrics::DrawAttempt(true));
+                            response
                         }
                     }
                 })
             });
-        app.at("/user/update")
+        metrics.route(&mut app, "/user/update")
+            .with(auth::Authentication)
             .put(|mut request: Request<Arc<Mutex<DataBase>>>| async move{
                 let body: Value = request.body_json().await?;
                 let object = body.as_object().unwrap();
-                let id : Id = get_field(object, "user_id");
+                let id = auth::acting_user(&request);
                 let name: String = get_field(object, "name");
+                let if_match = if_match(&request);
                 let mut guard = request.state().lock().unwrap();
-                Ok( if !guard.users.contains_key(&id)
-                {
-                    response_error("No such id")
-                }
-                else
+                Ok(match guard.users.get_mut(&id)
                 {
-                    guard.users.entry(id).and_modify(|k| *k = name);
-                    response_empty()
+                    None => response_error("No such id"),
+                    Some(user) =>
+                    {
+                        if !revision_matches(&if_match, user.revision)
+                        {
+                            response_precondition_failed(user.revision)
+                        }
+                        else
+                        {
+                            user.name = name;
+                            user.revision += 1;
+                            with_etag(response_empty(), user.revision)
+                        }
+                    }
                 })
             });
 
-        app.at("/user/delete")
-            .delete(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
-                let body: Value = request.body_json().await?;
-                let object = body.as_object().unwrap();
-                let user_id = get_field(object, "user_id");
+        metrics.route(&mut app, "/user/delete")
+            .with(auth::Authentication)
+            .delete(|request: Request<Arc<Mutex<DataBase>>>| async move {
+                let user_id = auth::acting_user(&request);
+                let if_match = if_match(&request);
                 let mut guard = request.state().lock().unwrap();
                 Ok(match guard.users.get(&user_id)
                 {
                     None => response_error("This user does not exist."),
-                    Some(_name) =>
+                    Some(user) if !revision_matches(&if_match, user.revision) => response_precondition_failed(user.revision),
+                    Some(_user) =>
                     {
-                        if guard.user_groups.len() > 0
+                        if !guard.user_groups.is_empty()
                         {
                             let iter1 = guard.user_groups.iter();
                             let iter2 = guard.user_groups.iter();
                             let collection = iter1.filter(|&x| x.0.user_id == user_id);
                             let collect_copy = iter2.filter(|&x| x.0.user_id == user_id);
-                            let closed_collect = collection.filter(|&x| guard.groups.get(&x.0.group_id).unwrap() == &true);
-                            let free_collect = collect_copy.filter(|&x| guard.groups.get(&x.0.group_id).unwrap() == &false);
+                            let closed_collect = collection.filter(|&x| guard.groups[&x.0.group_id].is_closed);
+                            let free_collect = collect_copy.filter(|&x| !guard.groups[&x.0.group_id].is_closed);
                             let mut admin_flag = false;
                             let mut vec:Vec<Id> = Vec::new();
                             let mut delete_vec=Vec::new();
                             for x in free_collect
                             {
-                                if x.1.access_level == Access::Admin && count_admins(x.0.group_id, &guard.user_groups) == 1
+                                if x.1.access_level == Access::Owner || (x.1.access_level.is_admin() && count_admins(x.0.group_id, &guard.user_groups) == 1)
                                 {
                                     admin_flag=true;
                                     vec.push(x.0.group_id);
                                 }
                                 else 
                                 {
-                                    delete_vec.push(UserGroupId{user_id: user_id, group_id: x.0.group_id});
+                                    delete_vec.push(UserGroupId{user_id, group_id: x.0.group_id});
                                 }
                             }   
                             if closed_collect.count() > 0
@@ -510,7 +2615,7 @@ fn main() -> Result<(), std::io::Error>
                                 {
                                     guard.user_groups.remove(&x);
                                 }
-                                if admin_flag == true
+                                if admin_flag
                                 {
                                     let mut string: String="User has closed groups. So he was deleted from opened groups, if he wasn't last admin. User cannot be delete from groups: ".to_string();
                                     for x in vec
@@ -531,9 +2636,13 @@ fn main() -> Result<(), std::io::Error>
                                 {
                                     guard.user_groups.remove(&x);
                                 }
-                                if admin_flag == false
+                                if !admin_flag
                                 {
                                     guard.users.remove(&user_id);
+                                    guard.tokens.retain(|_, id| *id != user_id);
+                                    guard.sessions.retain(|_, session| session.user_id != user_id);
+                                    guard.join_requests.retain(|key, _| key.user_id != user_id);
+                                    guard.bans.retain(|key| key.user_id != user_id);
                                     response_empty()
                                 }
                                 else 
@@ -551,13 +2660,176 @@ fn main() -> Result<(), std::io::Error>
                         else
                         {
                             guard.users.remove(&user_id);
+                            guard.tokens.retain(|_, id| *id != user_id);
+                            guard.sessions.retain(|_, session| session.user_id != user_id);
+                            guard.join_requests.retain(|key, _| key.user_id != user_id);
+                            guard.bans.retain(|key| key.user_id != user_id);
                             response_empty()
                         }
                     }
                 })
             });
         
-        app.listen("127.0.0.1:8080").await
+        // Сервер перестает принимать соединения, как только future с listen отбрасывается.
+        match futures::future::select(Box::pin(app.listen(config.bind.clone())), stop).await
+        {
+            futures::future::Either::Left((result, _)) => result,
+            futures::future::Either::Right(_) => Ok(()),
+        }
     };
-    futures::executor::block_on(f)
-}
\ No newline at end of file
+    futures::executor::block_on(f)?;
+
+    shutdown.begin();
+    tide::log::info!("shutdown requested, waiting for {} in-flight requests", shutdown.in_flight());
+    let unfinished = shutdown.wait(config.shutdown_timeout());
+    scheduler.stop();
+    persist.save(&state)?;
+    if unfinished > 0
+    {
+        tide::log::warn!("shutdown timed out with {} requests still in flight, state saved", unfinished);
+    }
+    else
+    {
+        tide::log::info!("shutdown complete, state saved");
+    }
+    Ok(())
+}
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn exchange_date_is_fixed_after_draw()
+    {
+        let today = NaiveDate::from_ymd_opt(2026, 12, 1).unwrap();
+        let mut group = Group::new(DEFAULT_ORGANIZATION);
+        group.exchange_date = NaiveDate::from_ymd_opt(2026, 12, 25);
+
+        assert!(check_exchange_date(&group, false, Some(today), today).is_ok());
+        assert!(check_exchange_date(&group, false, None, today).is_ok());
+        assert_eq!(check_exchange_date(&group, false, today.pred_opt(), today), Err((400, "exchange date cannot be in the past")));
+
+        // После жеребьевки дату нельзя ни приблизить, ни отодвинуть, ни убрать.
+        let changed = Err((409, "exchange date cannot be changed after the draw"));
+        assert_eq!(check_exchange_date(&group, true, Some(today), today), changed);
+        assert_eq!(check_exchange_date(&group, true, NaiveDate::from_ymd_opt(2026, 12, 31), today), changed);
+        assert_eq!(check_exchange_date(&group, true, None, today), changed);
+        assert!(check_exchange_date(&group, true, group.exchange_date, today).is_ok());
+    }
+
+    #[test]
+    fn bulk_check_runs_before_items()
+    {
+        let state = Arc::new(Mutex::new(DataBase::new()));
+        let items = [json!({"name": "Danis"})];
+        let create = |db: &mut DataBase, item: &Value| {
+            create_user(db, item["name"].as_str().unwrap().to_string(), DEFAULT_ORGANIZATION).map(|_| json!({}))
+        };
+
+        let response = run_bulk(&state, &items, false, |_| Some(response_missing_permission(Permission::Invite)), create);
+        assert_eq!(u16::from(response.status()), 403);
+        assert!(state.lock().unwrap().users.is_empty());
+
+        let response = run_bulk(&state, &items, false, |_| None, create);
+        assert_eq!(u16::from(response.status()), 200);
+        assert_eq!(state.lock().unwrap().users.len(), 1);
+    }
+
+    #[test]
+    fn operator_token_keeps_cli_operators()
+    {
+        let mut db = DataBase::new();
+        let (cli_id, cli_token) = create_user(&mut db, "ops".to_string(), DEFAULT_ORGANIZATION).unwrap();
+        db.users.get_mut(&cli_id).unwrap().is_operator = true;
+
+        ensure_operator(&mut db, "first".to_string());
+        let operator_id = db.tokens["first"];
+        assert_ne!(operator_id, cli_id);
+        assert_eq!(db.tokens[&cli_token], cli_id);
+
+        // Смена токена в настройках отзывает только прежний токен из настроек.
+        ensure_operator(&mut db, "second".to_string());
+        assert_eq!(db.tokens["second"], operator_id);
+        assert!(!db.tokens.contains_key("first"));
+        assert_eq!(db.tokens[&cli_token], cli_id);
+    }
+
+    fn query(cursor: Option<String>, limit: Option<usize>, order: Option<&str>) -> ListQuery
+    {
+        ListQuery {cursor, limit, sort: None, order: order.map(str::to_string), name: None, state: None}
+    }
+
+    fn names(page: &Value) -> Vec<&str>
+    {
+        page["items"].as_array().unwrap().iter().map(|item| item.as_str().unwrap()).collect()
+    }
+
+    // Имена с двоеточием: курсор делится по первому двоеточию, перед которым стоит ID.
+    fn sample() -> Vec<(SortKey, Value)>
+    {
+        ["b:2", "a", "c", "b:1", "a"].iter().enumerate()
+            .map(|(id, name)| ((name.to_string(), id as Id), json!(name)))
+            .collect()
+    }
+
+    #[test]
+    fn cursor_round_trip()
+    {
+        for key in [(String::new(), 7), ("b:1".to_string(), 3), ("".to_string(), 0)]
+        {
+            assert_eq!(decode_cursor(&encode_cursor(&key)), Some(key));
+        }
+        assert_eq!(decode_cursor("7"), None);
+        assert_eq!(decode_cursor("x:name"), None);
+    }
+
+    #[test]
+    fn paginate_checks_limit_and_order()
+    {
+        assert_eq!(paginate(sample(), &query(None, Some(0), None)), Err("wrong limit"));
+        assert_eq!(paginate(sample(), &query(None, Some(MAX_PAGE_LIMIT + 1), None)), Err("wrong limit"));
+        assert!(paginate(sample(), &query(None, Some(MAX_PAGE_LIMIT), None)).is_ok());
+        assert_eq!(paginate(sample(), &query(None, None, Some("up"))), Err("wrong order"));
+        assert_eq!(paginate(sample(), &query(Some("broken".to_string()), None, None)), Err("wrong format cursor"));
+
+        let page = paginate(sample(), &query(None, None, None)).unwrap();
+        assert_eq!(names(&page), ["a", "a", "b:1", "b:2", "c"]);
+        assert_eq!(page["next_cursor"], Value::Null);
+    }
+
+    // Проходит все страницы по next_cursor и возвращает имена в порядке выдачи.
+    fn walk(order: Option<&str>, limit: usize) -> Vec<String>
+    {
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop
+        {
+            let page = paginate(sample(), &query(cursor, Some(limit), order)).unwrap();
+            assert!(names(&page).len() <= limit);
+            seen.extend(names(&page).into_iter().map(str::to_string));
+            match page["next_cursor"].as_str()
+            {
+                Some(next) => cursor = Some(next.to_string()),
+                None => return seen,
+            }
+        }
+    }
+
+    #[test]
+    fn paginate_walks_to_last_page()
+    {
+        for limit in 1..=5
+        {
+            assert_eq!(walk(None, limit), ["a", "a", "b:1", "b:2", "c"]);
+            assert_eq!(walk(Some("desc"), limit), ["c", "b:2", "b:1", "a", "a"]);
+        }
+        // Последняя страница ровно заполнена: курсора на пустую страницу нет.
+        let page = paginate(sample(), &query(Some(encode_cursor(&("a".to_string(), 4))), Some(3), None)).unwrap();
+        assert_eq!(names(&page), ["b:1", "b:2", "c"]);
+        assert_eq!(page["next_cursor"], Value::Null);
+
+        let page = paginate(sample(), &query(Some(encode_cursor(&("b:1".to_string(), 3))), Some(10), Some("desc"))).unwrap();
+        assert_eq!(names(&page), ["a", "a"]);
+    }
+}
@@ -0,0 +1,334 @@
+// Служебные команды для сохраненной базы: выгрузка, загрузка, обновление формата, создание
+// оператора и проверка целостности.
+//
+// Команды работают с тем же хранилищем, что и сервер, поэтому нужен бэкенд `file`. Сервер держит
+// базу в памяти и перезаписывает файл целиком, так что изменяющие команды запускаются, пока
+// сервер остановлен.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use serde_json::json;
+use crate::config::{Backend, Config};
+use crate::storage::{self, Storage, SCHEMA_VERSION};
+use crate::{Access, Commitment, DataBase, Id, UserGroupId};
+
+pub const USAGE: &str = "usage: secret-santa-service [COMMAND] [OPTIONS]
+
+commands:
+  serve                  run the HTTP server (default)
+  dump [FILE]            write the stored database as JSON to FILE or stdout
+  load FILE [--force]    replace the stored database with a dump
+  migrate                upgrade the stored database to the current schema version
+  create-operator [NAME] add an operator user and print its token
+  check                  check the stored database for broken references
+
+options:
+  --config FILE          configuration file, see README for the other settings
+  --print-config         print the resulting configuration and exit";
+
+pub enum Failure
+{
+    // Неверная командная строка, выход с кодом 2.
+    Usage(String),
+    // Команда не выполнена, выход с кодом 1.
+    Failed(String),
+}
+
+impl From<std::io::Error> for Failure
+{
+    fn from(err: std::io::Error) -> Failure
+    {
+        Failure::Failed(err.to_string())
+    }
+}
+
+// Выполняет команду. Ok(false) значит, что команда отработала, но нашла ошибки.
+pub fn run(command: &str, args: &[String], config: &Config) -> Result<bool, Failure>
+{
+    if !matches!(command, "dump" | "load" | "migrate" | "create-operator" | "check")
+    {
+        return Err(Failure::Usage(format!("unknown command {}", command)));
+    }
+    if config.storage.backend != Backend::File
+    {
+        return Err(Failure::Failed("needs storage.backend = \"file\"".to_string()));
+    }
+    let storage = Storage::new(&config.storage);
+    match (command, args)
+    {
+        ("dump", []) => dump(&storage, None),
+        ("dump", [path]) => dump(&storage, Some(Path::new(path))),
+        ("load", [path]) => load(&storage, Path::new(path), false),
+        ("load", [path, force]) | ("load", [force, path]) if force == "--force" => load(&storage, Path::new(path), true),
+        ("migrate", []) => migrate(&storage, &config.storage.path),
+        ("create-operator", []) => create_operator(&storage, "operator"),
+        ("create-operator", [name]) => create_operator(&storage, name),
+        ("check", []) => check(&storage),
+        _ => Err(Failure::Usage(format!("wrong arguments for {}: {}", command, args.join(" ")))),
+    }
+}
+
+fn load_existing(storage: &Storage) -> Result<(u32, DataBase), Failure>
+{
+    storage.load_any()?.ok_or_else(|| Failure::Failed(format!("{} does not exist", storage.describe())))
+}
+
+fn dump(storage: &Storage, output: Option<&Path>) -> Result<bool, Failure>
+{
+    let (_, data) = load_existing(storage)?;
+    let value: serde_json::Value = serde_json::from_slice(&storage::encode(&data)).unwrap();
+    let text = serde_json::to_string_pretty(&value).unwrap() + "\n";
+    match output
+    {
+        Some(path) => std::fs::write(path, text)?,
+        None => print!("{}", text),
+    }
+    Ok(true)
+}
+
+fn load(storage: &Storage, input: &Path, force: bool) -> Result<bool, Failure>
+{
+    let bytes = std::fs::read(input).map_err(|err| Failure::Failed(format!("failed to read {}: {}", input.display(), err)))?;
+    let (_, data) = storage::decode(&bytes).map_err(|msg| Failure::Failed(format!("failed to read {}: {}", input.display(), msg)))?;
+    let problems = problems(&data);
+    if !problems.is_empty()
+    {
+        print_problems(&problems);
+        return Err(Failure::Failed(format!("{} is not consistent, nothing loaded", input.display())));
+    }
+    // Файл, который не читается, тоже затирается только с --force.
+    if !force && storage.load_any().map_or(true, |existing| existing.is_some())
+    {
+        return Err(Failure::Failed(format!("{} already has data, pass --force to replace it", storage.describe())));
+    }
+    storage.save(&data)?;
+    println!("loaded {} users and {} groups into {}", data.users.len(), data.groups.len(), storage.describe());
+    Ok(true)
+}
+
+fn migrate(storage: &Storage, path: &Path) -> Result<bool, Failure>
+{
+    let (version, data) = load_existing(storage)?;
+    if version == SCHEMA_VERSION
+    {
+        println!("{} is already at schema version {}", storage.describe(), SCHEMA_VERSION);
+        return Ok(true);
+    }
+    // Старый файл остается рядом, пока новый не проверен на живом сервере.
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", version));
+    std::fs::copy(path, &backup)?;
+    storage.save(&data)?;
+    println!(
+        "migrated {} from schema version {} to {}, the old file is saved as {}",
+        storage.describe(),
+        version,
+        SCHEMA_VERSION,
+        Path::new(&backup).display()
+    );
+    Ok(true)
+}
+
+fn create_operator(storage: &Storage, name: &str) -> Result<bool, Failure>
+{
+    // Оператора не стоит добавлять в базу старой версии: сервер ее все равно не прочитает.
+    let mut data = storage.load()?.unwrap_or_else(DataBase::new);
+    let (id, token) = crate::create_user(&mut data, name.to_string(), crate::DEFAULT_ORGANIZATION).map_err(|msg| Failure::Failed(msg.to_string()))?;
+    data.users.get_mut(&id).unwrap().is_operator = true;
+    storage.save(&data)?;
+    println!("{}", json!({"id": id, "token": token}));
+    Ok(true)
+}
+
+fn check(storage: &Storage) -> Result<bool, Failure>
+{
+    let (version, data) = load_existing(storage)?;
+    if version != SCHEMA_VERSION
+    {
+        println!("schema version {} is outdated, run `secret-santa-service migrate`", version);
+    }
+    let problems = problems(&data);
+    if problems.is_empty()
+    {
+        println!(
+            "ok: {} users, {} groups, {} memberships",
+            data.users.len(),
+            data.groups.len(),
+            data.user_groups.len()
+        );
+    }
+    print_problems(&problems);
+    Ok(problems.is_empty() && version == SCHEMA_VERSION)
+}
+
+fn print_problems(problems: &[String])
+{
+    for problem in problems
+    {
+        println!("{}", problem);
+    }
+}
+
+// Нарушения связей между записями базы, отсортированные для стабильного вывода.
+pub fn problems(db: &DataBase) -> Vec<String>
+{
+    let mut problems = Vec::new();
+    let user_exists = |id: &Id| db.users.contains_key(id);
+    let group_exists = |id: &Id| db.groups.contains_key(id);
+
+    if let Some(id) = db.users.keys().find(|&&id| id >= db.users_max_id)
+    {
+        problems.push(format!("user {} is not below users_max_id {}", id, db.users_max_id));
+    }
+    if let Some(id) = db.groups.keys().find(|&&id| id >= db.groups_max_id)
+    {
+        problems.push(format!("group {} is not below groups_max_id {}", id, db.groups_max_id));
+    }
+    if let Some(id) = db.organizations.keys().find(|&&id| id >= db.organizations_max_id)
+    {
+        problems.push(format!("organization {} is not below organizations_max_id {}", id, db.organizations_max_id));
+    }
+    for (id, user) in &db.users
+    {
+        if !db.organizations.contains_key(&user.organization_id)
+        {
+            problems.push(format!("user {} is in missing organization {}", id, user.organization_id));
+        }
+    }
+    for (id, group) in &db.groups
+    {
+        if !db.organizations.contains_key(&group.organization_id)
+        {
+            problems.push(format!("group {} is in missing organization {}", id, group.organization_id));
+        }
+    }
+
+    if let Some(configured) = &db.configured_operator
+    {
+        if !db.users.get(&configured.user_id).is_some_and(|user| user.is_operator)
+        {
+            problems.push(format!("configured operator {} is missing or not an operator", configured.user_id));
+        }
+    }
+
+    let mut logins = HashSet::new();
+    for (id, user) in &db.users
+    {
+        if let Some(credentials) = &user.credentials
+        {
+            if !logins.insert(credentials.login.as_str())
+            {
+                problems.push(format!("user {} has login {:?} that is already taken", id, credentials.login));
+            }
+        }
+    }
+
+    let mut members: HashMap<Id, HashSet<Id>> = HashMap::new();
+    let mut owners: HashMap<Id, usize> = HashMap::new();
+    for (key, props) in &db.user_groups
+    {
+        if !user_exists(&key.user_id)
+        {
+            problems.push(format!("membership of missing user {} in group {}", key.user_id, key.group_id));
+        }
+        if !group_exists(&key.group_id)
+        {
+            problems.push(format!("membership of user {} in missing group {}", key.user_id, key.group_id));
+        }
+        if let (Some(user), Some(group)) = (db.users.get(&key.user_id), db.groups.get(&key.group_id))
+        {
+            if user.organization_id != group.organization_id
+            {
+                problems.push(format!("user {} is in group {} of another organization", key.user_id, key.group_id));
+            }
+        }
+        members.entry(key.group_id).or_default().insert(key.user_id);
+        if props.access_level == Access::Owner
+        {
+            *owners.entry(key.group_id).or_insert(0) += 1;
+        }
+    }
+
+    for (&group_id, group) in &db.groups
+    {
+        // После удаления участников у архивной группы нет и владельца.
+        match owners.get(&group_id).copied().unwrap_or(0)
+        {
+            0 if group.purged_at.is_some() => (),
+            1 => (),
+            count => problems.push(format!("group {} has {} owners", group_id, count)),
+        }
+        let group_members = members.remove(&group_id).unwrap_or_default();
+        let assignments = crate::assignments(db, group_id);
+        if assignments.is_empty()
+        {
+            if group.commitment.is_some()
+            {
+                problems.push(format!("group {} has a commitment but no draw", group_id));
+            }
+            continue;
+        }
+        if assignments.len() != group_members.len()
+        {
+            problems.push(format!("group {} has {} members but {} assignments", group_id, group_members.len(), assignments.len()));
+        }
+        let mut recipients = HashSet::new();
+        for &(santa_id, recipient_id) in &assignments
+        {
+            if santa_id == recipient_id
+            {
+                problems.push(format!("user {} is their own santa in group {}", santa_id, group_id));
+            }
+            if !group_members.contains(&recipient_id)
+            {
+                problems.push(format!("user {} in group {} is santa for {}, who is not a member", santa_id, group_id, recipient_id));
+            }
+            if !recipients.insert(recipient_id)
+            {
+                problems.push(format!("user {} has more than one santa in group {}", recipient_id, group_id));
+            }
+        }
+        if let Some(commitment) = &group.commitment
+        {
+            if Commitment::hash(&commitment.salt, &assignments) != commitment.hash
+            {
+                problems.push(format!("group {} assignments do not match its commitment", group_id));
+            }
+        }
+    }
+
+    for (token, user_id) in &db.tokens
+    {
+        if !user_exists(user_id)
+        {
+            // Токен целиком в вывод не попадает.
+            problems.push(format!("token {}... belongs to missing user {}", &token[..token.len().min(6)], user_id));
+        }
+    }
+    for session in db.sessions.values()
+    {
+        if !user_exists(&session.user_id)
+        {
+            problems.push(format!("session belongs to missing user {}", session.user_id));
+        }
+    }
+    for (code, invite) in &db.invites
+    {
+        if !group_exists(&invite.group_id)
+        {
+            problems.push(format!("invite {} is for missing group {}", code, invite.group_id));
+        }
+    }
+    let pending = db.join_requests.keys().map(|key| ("join request", key));
+    let banned = db.bans.iter().map(|key| ("ban", key));
+    for (kind, UserGroupId { user_id, group_id }) in pending.chain(banned)
+    {
+        if !user_exists(user_id) || !group_exists(group_id)
+        {
+            problems.push(format!("{} of user {} in group {} refers to a missing record", kind, user_id, group_id));
+        }
+    }
+
+    problems.sort();
+    problems
+}
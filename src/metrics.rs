@@ -0,0 +1,252 @@
+// Метрики сервера в текстовом формате Prometheus для /metrics.
+//
+// Запросы считаются по методу, шаблону маршрута и коду ответа, время обработки собирается
+// в гистограмму по методу и маршруту. Шаблоны маршрутов регистрируются через Metrics::route,
+// поэтому ID в путях не плодят отдельные ряды. Путь, не совпавший ни с одним шаблоном,
+// считается маршрутом `unmatched`.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use tide::{Middleware, Next, Request};
+
+// Верхние границы корзин гистограммы времени обработки, в секундах.
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// Обработчик жеребьевки кладет в расширения ответа, удалось ли провести жеребьевку. Ответы без
+// него, например отказ в доступе или повтор по Idempotency-Key, жеребьевкой не считаются.
+#[derive(Clone, Copy)]
+pub struct DrawAttempt(pub bool);
+
+#[derive(Default)]
+struct Histogram
+{
+    // Число наблюдений не больше каждой границы из LATENCY_BUCKETS.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram
+{
+    fn observe(&mut self, value: f64)
+    {
+        if self.buckets.is_empty()
+        {
+            self.buckets = vec![0; LATENCY_BUCKETS.len()];
+        }
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS)
+        {
+            if value <= *bound
+            {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct Counters
+{
+    // (метод, маршрут, код ответа) -> число запросов.
+    requests: HashMap<(String, String, u16), u64>,
+    // (метод, маршрут) -> время обработки.
+    latency: HashMap<(String, String), Histogram>,
+    draws: u64,
+    draw_failures: u64,
+}
+
+// Значения, которые берутся из базы в момент запроса /metrics.
+pub struct Gauges
+{
+    pub users: usize,
+    pub open_groups: usize,
+    pub closed_groups: usize,
+    pub archived_groups: usize,
+    pub memberships: usize,
+}
+
+#[derive(Clone, Default)]
+pub struct Metrics
+{
+    // Шаблоны маршрутов, разбитые на сегменты.
+    routes: Arc<RwLock<Vec<Vec<String>>>>,
+    counters: Arc<Mutex<Counters>>,
+}
+
+fn segments(path: &str) -> Vec<&str>
+{
+    path.trim_matches('/').split('/').collect()
+}
+
+impl Metrics
+{
+    pub fn new() -> Metrics
+    {
+        Metrics::default()
+    }
+
+    // То же, что app.at(path), но запоминает шаблон маршрута для меток.
+    pub fn route<'a, State>(&self, app: &'a mut tide::Server<State>, path: &str) -> tide::Route<'a, State>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        self.routes.write().unwrap().push(segments(path).into_iter().map(String::from).collect());
+        app.at(path)
+    }
+
+    // Шаблон маршрута, с которым совпадает путь. Сегменты вида `:name` совпадают с любым значением.
+    // Из нескольких совпавших шаблонов, как и в роутере tide, выбирается тот, у которого раньше
+    // идет постоянный сегмент на месте `:name` другого.
+    fn route_label(&self, path: &str) -> String
+    {
+        let path = segments(path);
+        let routes = self.routes.read().unwrap();
+        let matched = routes.iter()
+            .filter(|route| {
+                route.len() == path.len()
+                    && route.iter().zip(&path).all(|(pattern, segment)| pattern.starts_with(':') || pattern == segment)
+            })
+            .min_by_key(|route| route.iter().map(|pattern| pattern.starts_with(':')).collect::<Vec<bool>>());
+        match matched
+        {
+            Some(route) => format!("/{}", route.join("/")),
+            None => "unmatched".to_string(),
+        }
+    }
+
+    // Проведенная или не удавшаяся жеребьевка.
+    pub fn record_draw(&self, success: bool)
+    {
+        let mut counters = self.counters.lock().unwrap();
+        if success
+        {
+            counters.draws += 1;
+        }
+        else
+        {
+            counters.draw_failures += 1;
+        }
+    }
+
+    pub fn render(&self, gauges: &Gauges) -> String
+    {
+        let counters = self.counters.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP http_requests_total Number of HTTP requests by method, route and status.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        let mut requests: Vec<_> = counters.requests.iter().collect();
+        requests.sort();
+        for ((method, route, status), count) in requests
+        {
+            let _ = writeln!(out, "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}", method, route, status, count);
+        }
+
+        out.push_str("# HELP http_request_duration_seconds Time to handle HTTP requests by method and route.\n");
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+        let mut latency: Vec<_> = counters.latency.iter().collect();
+        latency.sort_by(|a, b| a.0.cmp(b.0));
+        for ((method, route), histogram) in latency
+        {
+            let labels = format!("method=\"{}\",route=\"{}\"", method, route);
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets)
+            {
+                let _ = writeln!(out, "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, count);
+            }
+            let _ = writeln!(out, "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, histogram.count);
+            let _ = writeln!(out, "http_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+            let _ = writeln!(out, "http_request_duration_seconds_count{{{}}} {}", labels, histogram.count);
+        }
+
+        out.push_str("# HELP secret_santa_users Number of users.\n");
+        out.push_str("# TYPE secret_santa_users gauge\n");
+        let _ = writeln!(out, "secret_santa_users {}", gauges.users);
+        out.push_str("# HELP secret_santa_groups Number of groups by state.\n");
+        out.push_str("# TYPE secret_santa_groups gauge\n");
+        let _ = writeln!(out, "secret_santa_groups{{state=\"open\"}} {}", gauges.open_groups);
+        let _ = writeln!(out, "secret_santa_groups{{state=\"closed\"}} {}", gauges.closed_groups);
+        let _ = writeln!(out, "secret_santa_groups{{state=\"archived\"}} {}", gauges.archived_groups);
+        out.push_str("# HELP secret_santa_memberships Number of group memberships.\n");
+        out.push_str("# TYPE secret_santa_memberships gauge\n");
+        let _ = writeln!(out, "secret_santa_memberships {}", gauges.memberships);
+        out.push_str("# HELP secret_santa_draws_total Number of draws performed.\n");
+        out.push_str("# TYPE secret_santa_draws_total counter\n");
+        let _ = writeln!(out, "secret_santa_draws_total {}", counters.draws);
+        out.push_str("# HELP secret_santa_draw_failures_total Number of draws that could not be performed for the group.\n");
+        out.push_str("# TYPE secret_santa_draw_failures_total counter\n");
+        let _ = writeln!(out, "secret_santa_draw_failures_total {}", counters.draw_failures);
+        out
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Metrics
+{
+    async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> tide::Result
+    {
+        let started = Instant::now();
+        let method = request.method().to_string();
+        let route = self.route_label(request.url().path());
+
+        let response = next.run(request).await;
+
+        if let Some(&DrawAttempt(success)) = response.ext::<DrawAttempt>()
+        {
+            self.record_draw(success);
+        }
+        let status: u16 = response.status().into();
+        let mut counters = self.counters.lock().unwrap();
+        *counters.requests.entry((method.clone(), route.clone(), status)).or_insert(0) += 1;
+        counters.latency.entry((method, route)).or_default().observe(started.elapsed().as_secs_f64());
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::Metrics;
+
+    fn metrics(routes: &[&str]) -> Metrics
+    {
+        let metrics = Metrics::new();
+        let mut app = tide::new();
+        for route in routes
+        {
+            metrics.route(&mut app, route);
+        }
+        metrics
+    }
+
+    #[test]
+    fn prefers_static_segments()
+    {
+        // Порядок регистрации не важен, как и в роутере tide.
+        for routes in [["/group/requests/:group_id", "/group/requests/approve"], ["/group/requests/approve", "/group/requests/:group_id"]]
+        {
+            let metrics = metrics(&routes);
+            assert_eq!(metrics.route_label("/group/requests/approve"), "/group/requests/approve");
+            assert_eq!(metrics.route_label("/group/requests/7"), "/group/requests/:group_id");
+        }
+    }
+
+    #[test]
+    fn prefers_earlier_static_segment()
+    {
+        let metrics = metrics(&["/group/:group_id/list", "/group/info/:group_id"]);
+        assert_eq!(metrics.route_label("/group/info/list"), "/group/info/:group_id");
+    }
+
+    #[test]
+    fn unmatched_paths()
+    {
+        let metrics = metrics(&["/group/requests/:group_id", "/group/requests/approve"]);
+        assert_eq!(metrics.route_label("/group/requests"), "unmatched");
+        assert_eq!(metrics.route_label("/group/requests/7/extra"), "unmatched");
+        assert_eq!(metrics.route_label("/user/create"), "unmatched");
+    }
+}
@@ -0,0 +1,216 @@
+// Ограничение частоты запросов одного клиента.
+//
+// Каждый запрос расходует корзину IP адреса соединения и, если он пришел с действующим токеном
+// из `Authorization: Bearer` или cookie сессии, еще и корзину этого токена или сессии. Выдуманный
+// токен своей корзины не получает, иначе каждый запрос с новым токеном обходил бы лимит.
+// Для чтения (GET/HEAD) и изменения отдельные корзины: корзина вмещает `requests` запросов
+// и полностью наполняется за `period`. Адрес клиента за балансировщиком берется из
+// auth::ClientAddress. Проверки балансировщика и сбор метрик не ограничиваются.
+//
+// Обработчик, который за один запрос выполняет много изменений, списывает их отдельно через charge.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tide::http::Method;
+use tide::{Middleware, Next, Request, Response};
+use crate::DataBase;
+
+#[derive(Clone, Copy)]
+pub struct Quota
+{
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Quota
+{
+    pub fn per_minute(requests: u32) -> Quota
+    {
+        Quota { requests, period: Duration::from_secs(60) }
+    }
+
+    // Сколько запросов добавляется в корзину за секунду.
+    fn rate(&self) -> f64
+    {
+        self.requests as f64 / self.period.as_secs_f64()
+    }
+}
+
+// Пути, которые опрашивают балансировщик и сборщик метрик, а не клиенты.
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/readyz", "/metrics"];
+
+struct Bucket
+{
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind
+{
+    Read,
+    Write,
+}
+
+struct Buckets
+{
+    buckets: HashMap<(String, Kind), Bucket>,
+    last_sweep: Instant,
+}
+
+struct Limiter
+{
+    read: Quota,
+    write: Quota,
+    state: Mutex<Buckets>,
+}
+
+impl Limiter
+{
+    fn quota(&self, kind: Kind) -> Quota
+    {
+        match kind
+        {
+            Kind::Read => self.read,
+            Kind::Write => self.write,
+        }
+    }
+
+    // Забирает по count запросов из корзин всех ключей клиента, только если их хватает в каждой.
+    // Иначе возвращает, через сколько секунд они появятся во всех корзинах.
+    fn take(&self, clients: &[String], kind: Kind, count: u32) -> Result<(), u64>
+    {
+        let quota = self.quota(kind);
+        let capacity = quota.requests as f64;
+        let count = count as f64;
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        // Полные корзины ничем не отличаются от отсутствующих, их можно забыть.
+        if now.duration_since(state.last_sweep) >= self.read.period.max(self.write.period)
+        {
+            let (read, write) = (self.read, self.write);
+            state.buckets.retain(|(_, kind), bucket| {
+                let quota = if *kind == Kind::Read { read } else { write };
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * quota.rate() < quota.requests as f64
+            });
+            state.last_sweep = now;
+        }
+
+        let mut lowest = capacity;
+        for client in clients
+        {
+            let bucket = state.buckets.entry((client.clone(), kind)).or_insert(Bucket { tokens: capacity, updated: now });
+            bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * quota.rate()).min(capacity);
+            bucket.updated = now;
+            lowest = lowest.min(bucket.tokens);
+        }
+        if lowest < count
+        {
+            return Err(((count - lowest) / quota.rate()).ceil() as u64);
+        }
+        for client in clients
+        {
+            state.buckets.get_mut(&(client.clone(), kind)).unwrap().tokens -= count;
+        }
+        Ok(())
+    }
+}
+
+fn response_too_many(retry_after: Option<u64>) -> Response
+{
+    match retry_after
+    {
+        Some(retry_after) =>
+        {
+            let mut response = crate::response_error_with_status(429, "too many requests");
+            response.insert_header("Retry-After", retry_after.max(1).to_string());
+            response
+        },
+        None => crate::response_error_with_status(429, "batch is larger than the rate limit allows"),
+    }
+}
+
+// Корзины клиента текущего запроса, через них обработчик списывает дополнительные изменения.
+#[derive(Clone)]
+struct Charge
+{
+    limiter: Arc<Limiter>,
+    clients: Vec<String>,
+}
+
+// Списывает с клиента еще count изменяющих запросов. Если их не хватает, возвращает ответ 429.
+// Без ограничения частоты ничего не делает.
+pub fn charge<State>(request: &Request<State>, count: usize) -> Option<Response>
+{
+    match request.ext::<Charge>()
+    {
+        None => None,
+        // Вместе с уже списанным самим запросом в корзину должно поместиться count + 1.
+        Some(charge) if count >= charge.limiter.write.requests as usize => Some(response_too_many(None)),
+        Some(charge) => charge.limiter.take(&charge.clients, Kind::Write, count as u32).err().map(|retry_after| response_too_many(Some(retry_after))),
+    }
+}
+
+pub struct RateLimit
+{
+    limiter: Arc<Limiter>,
+}
+
+impl RateLimit
+{
+    pub fn new(read: Quota, write: Quota) -> RateLimit
+    {
+        RateLimit {
+            limiter: Arc::new(Limiter {
+                read,
+                write,
+                state: Mutex::new(Buckets { buckets: HashMap::new(), last_sweep: Instant::now() }),
+            }),
+        }
+    }
+}
+
+// Ключи корзин клиента: IP адрес и, если есть, действующий токен или сессия.
+fn client_keys(request: &Request<Arc<Mutex<DataBase>>>) -> Vec<String>
+{
+    let mut keys = vec![format!("address {}", crate::auth::client_address(request))];
+    let token = crate::auth::bearer_token(request);
+    let session = crate::auth::session_id(request);
+    let guard = request.state().lock().unwrap();
+    match (token, session)
+    {
+        (Some(token), _) if guard.tokens.contains_key(&token) => keys.push(format!("token {}", token)),
+        (None, Some(session)) if guard.sessions.get(&session).is_some_and(|session| !session.is_expired()) =>
+        {
+            keys.push(format!("session {}", session));
+        },
+        _ => (),
+    }
+    keys
+}
+
+#[tide::utils::async_trait]
+impl Middleware<Arc<Mutex<DataBase>>> for RateLimit
+{
+    async fn handle(&self, mut request: Request<Arc<Mutex<DataBase>>>, next: Next<'_, Arc<Mutex<DataBase>>>) -> tide::Result
+    {
+        if EXEMPT_PATHS.contains(&request.url().path())
+        {
+            return Ok(next.run(request).await);
+        }
+        let kind = match request.method()
+        {
+            Method::Get | Method::Head => Kind::Read,
+            _ => Kind::Write,
+        };
+        let clients = client_keys(&request);
+        if let Err(retry_after) = self.limiter.take(&clients, kind, 1)
+        {
+            return Ok(response_too_many(Some(retry_after)));
+        }
+        request.set_ext(Charge { limiter: self.limiter.clone(), clients });
+        Ok(next.run(request).await)
+    }
+}
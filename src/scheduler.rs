@@ -0,0 +1,268 @@
+// Планировщик: закрывает группы после срока вступления, сам проводит запланированные жеребьевки
+// и, если включено хранение по срокам, архивирует старые группы и удаляет из них участников.
+//
+// Работает в отдельном потоке и раз в `scheduler.interval_secs` секунд проверяет сроки групп.
+// Расписание хранится в самих группах, поэтому переживает перезапуск: первый проход после старта
+// выполняет все, что наступило, пока сервер не работал.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use chrono::{DateTime, Days, Utc};
+use crate::config::RetentionConfig;
+use crate::metrics::Metrics;
+use crate::storage::Persist;
+use crate::{DataBase, Id};
+
+pub struct Scheduler
+{
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Scheduler
+{
+    pub fn spawn(
+        state: Arc<Mutex<DataBase>>,
+        persist: Persist,
+        metrics: Metrics,
+        interval: Duration,
+        retention: Option<RetentionConfig>,
+    ) -> Scheduler
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let thread = std::thread::spawn(move || {
+            while !stop_flag.load(Ordering::SeqCst)
+            {
+                let changed = run_due(&mut state.lock().unwrap(), Utc::now(), &metrics, retention.as_ref());
+                if changed
+                {
+                    if let Err(err) = persist.save(&state)
+                    {
+                        log::error!(target: "scheduler", "failed to save state: {}", err);
+                    }
+                }
+                // Остановка будит поток раньше времени.
+                std::thread::park_timeout(interval);
+            }
+        });
+        Scheduler { stop, thread }
+    }
+
+    pub fn stop(self)
+    {
+        self.stop.store(true, Ordering::SeqCst);
+        self.thread.thread().unpark();
+        let _ = self.thread.join();
+    }
+}
+
+// Выполняет все наступившие к `now` события. Возвращает, изменилась ли база.
+pub fn run_due(db: &mut DataBase, now: DateTime<Utc>, metrics: &Metrics, retention: Option<&RetentionConfig>) -> bool
+{
+    let mut group_ids: Vec<Id> = db.groups.keys().copied().collect();
+    group_ids.sort();
+    let mut changed = false;
+    for group_id in group_ids
+    {
+        if let Some(retention) = retention
+        {
+            changed |= apply_retention(db, group_id, now, retention);
+        }
+        let group = db.groups.get_mut(&group_id).unwrap();
+        if !group.is_closed && group.join_deadline.is_some_and(|deadline| deadline <= now)
+        {
+            group.is_closed = true;
+            group.revision += 1;
+            changed = true;
+            log::info!(target: "scheduler", group_id = group_id; "join deadline passed, group closed");
+        }
+        if group.draw_at.is_none_or(|draw_at| draw_at > now)
+        {
+            continue;
+        }
+        changed = true;
+        let members = db.user_groups.keys().filter(|key| key.group_id == group_id).count();
+        if crate::is_drawn(db, group_id)
+        {
+            db.groups.get_mut(&group_id).unwrap().draw_at = None;
+        }
+        else if let Some(problem) = crate::draw_problem(db, group_id)
+        {
+            let group = db.groups.get_mut(&group_id).unwrap();
+            group.draw_at = None;
+            group.scheduled_draw_error = Some(problem.to_string());
+            group.revision += 1;
+            metrics.record_draw(false);
+            log::warn!(target: "scheduler", group_id = group_id, members = members; "scheduled draw skipped, {}", problem);
+        }
+        else
+        {
+            crate::draw(db, group_id);
+            metrics.record_draw(true);
+            log::info!(target: "scheduler", group_id = group_id, members = members; "scheduled draw done");
+        }
+    }
+    changed
+}
+
+// Архивирует группу через archive_after_days после даты обмена и удаляет из нее участников
+// через purge_after_days после архивации. Возвращает, изменилась ли группа.
+fn apply_retention(db: &mut DataBase, group_id: Id, now: DateTime<Utc>, retention: &RetentionConfig) -> bool
+{
+    let group = &db.groups[&group_id];
+    match (group.archived_at, group.purged_at)
+    {
+        (None, _) =>
+        {
+            let archive_on = group.exchange_date
+                .and_then(|date| date.checked_add_days(Days::new(retention.archive_after_days.into())));
+            if archive_on.is_none_or(|archive_on| now.date_naive() < archive_on)
+            {
+                return false;
+            }
+            crate::archive_group(db, group_id, now);
+            log::info!(target: "scheduler", group_id = group_id; "group archived");
+            true
+        },
+        (Some(archived_at), None) =>
+        {
+            if archived_at.checked_add_days(Days::new(retention.purge_after_days.into())).is_none_or(|purge_at| now < purge_at)
+            {
+                return false;
+            }
+            crate::purge_group(db, group_id, now);
+            log::info!(target: "scheduler", group_id = group_id; "archived group purged");
+            true
+        },
+        (Some(_), Some(_)) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use chrono::{DateTime, Duration, NaiveDate, Utc};
+    use crate::config::RetentionConfig;
+    use crate::metrics::Metrics;
+    use crate::{Access, DataBase, Group, Id, UserGroupId, UserGroupProps, DEFAULT_ORGANIZATION};
+    use super::run_due;
+
+    fn time(text: &str) -> DateTime<Utc>
+    {
+        text.parse().unwrap()
+    }
+
+    // База с одной группой из members участников, первый из них - владелец.
+    fn group_with_members(members: usize) -> (DataBase, Id)
+    {
+        let mut db = DataBase::new();
+        db.groups.insert(0, Group::new(DEFAULT_ORGANIZATION));
+        db.groups_max_id = 1;
+        for index in 0..members
+        {
+            let (user_id, _) = crate::create_user(&mut db, format!("user {}", index), DEFAULT_ORGANIZATION).unwrap();
+            let access = if index == 0 { Access::Owner } else { Access::User };
+            db.user_groups.insert(UserGroupId { user_id, group_id: 0 }, UserGroupProps::new(access));
+        }
+        (db, 0)
+    }
+
+    #[test]
+    fn closes_group_after_join_deadline()
+    {
+        let (mut db, group_id) = group_with_members(1);
+        let deadline = time("2026-12-01T12:00:00Z");
+        db.groups.get_mut(&group_id).unwrap().join_deadline = Some(deadline);
+
+        assert!(!run_due(&mut db, deadline - Duration::seconds(1), &Metrics::new(), None));
+        assert!(!db.groups[&group_id].is_closed);
+        assert!(run_due(&mut db, deadline, &Metrics::new(), None));
+        assert!(db.groups[&group_id].is_closed);
+        // Повторный проход ничего не меняет.
+        assert!(!run_due(&mut db, deadline + Duration::hours(1), &Metrics::new(), None));
+    }
+
+    #[test]
+    fn runs_scheduled_draw()
+    {
+        let (mut db, group_id) = group_with_members(3);
+        let draw_at = time("2026-12-10T18:00:00Z");
+        db.groups.get_mut(&group_id).unwrap().draw_at = Some(draw_at);
+
+        assert!(!run_due(&mut db, draw_at - Duration::seconds(1), &Metrics::new(), None));
+        assert!(!crate::is_drawn(&db, group_id));
+        assert!(run_due(&mut db, draw_at, &Metrics::new(), None));
+        assert!(crate::is_drawn(&db, group_id));
+        let group = &db.groups[&group_id];
+        assert!(group.is_closed);
+        assert!(group.draw_at.is_none());
+        assert!(group.scheduled_draw_error.is_none());
+        assert!(group.commitment.is_some());
+    }
+
+    #[test]
+    fn skips_draw_without_enough_members()
+    {
+        let (mut db, group_id) = group_with_members(1);
+        let draw_at = time("2026-12-10T18:00:00Z");
+        db.groups.get_mut(&group_id).unwrap().draw_at = Some(draw_at);
+
+        assert!(run_due(&mut db, draw_at, &Metrics::new(), None));
+        assert!(!crate::is_drawn(&db, group_id));
+        let group = &db.groups[&group_id];
+        assert!(group.draw_at.is_none());
+        assert_eq!(group.scheduled_draw_error.as_deref(), Some("not enough members"));
+    }
+
+    fn retention() -> RetentionConfig
+    {
+        RetentionConfig { archive_after_days: 30, purge_after_days: 365 }
+    }
+
+    #[test]
+    fn archives_after_exchange_date()
+    {
+        let (mut db, group_id) = group_with_members(2);
+        db.groups.get_mut(&group_id).unwrap().exchange_date = NaiveDate::from_ymd_opt(2026, 12, 25);
+
+        // Без настроек хранения группы не архивируются.
+        assert!(!run_due(&mut db, time("2027-06-01T00:00:00Z"), &Metrics::new(), None));
+        assert!(!run_due(&mut db, time("2027-01-23T23:59:59Z"), &Metrics::new(), Some(&retention())));
+        assert!(db.groups[&group_id].archived_at.is_none());
+
+        let now = time("2027-01-24T00:00:00Z");
+        assert!(run_due(&mut db, now, &Metrics::new(), Some(&retention())));
+        let group = &db.groups[&group_id];
+        assert_eq!(group.archived_at, Some(now));
+        assert!(group.is_closed);
+        // Участники остаются до удаления.
+        assert_eq!(db.user_groups.len(), 2);
+    }
+
+    #[test]
+    fn purges_archived_group_and_keeps_stats()
+    {
+        let (mut db, group_id) = group_with_members(3);
+        crate::draw(&mut db, group_id);
+        db.user_groups.values_mut().next().unwrap().wishlist = "book".to_string();
+        let archived_at = time("2027-01-24T00:00:00Z");
+        crate::archive_group(&mut db, group_id, archived_at);
+
+        assert!(!run_due(&mut db, archived_at + Duration::days(365) - Duration::seconds(1), &Metrics::new(), Some(&retention())));
+        assert!(db.groups[&group_id].purged_at.is_none());
+
+        let now = archived_at + Duration::days(365);
+        assert!(run_due(&mut db, now, &Metrics::new(), Some(&retention())));
+        let group = &db.groups[&group_id];
+        assert_eq!(group.purged_at, Some(now));
+        assert!(group.commitment.is_none());
+        let stats = group.stats.as_ref().unwrap();
+        assert_eq!((stats.members, stats.wishlists, stats.was_drawn), (3, 1, true));
+        assert!(db.user_groups.is_empty());
+        // Удаленная группа больше не меняется.
+        assert!(!run_due(&mut db, now + Duration::days(365), &Metrics::new(), Some(&retention())));
+    }
+}
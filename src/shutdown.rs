@@ -0,0 +1,103 @@
+// Плавная остановка сервера по SIGINT и SIGTERM.
+//
+// После сигнала сервер перестает принимать соединения, на новые запросы в открытых соединениях
+// отвечает 503 и ждет завершения уже начатых запросов, но не дольше заданного времени.
+// Повторный сигнал завершает процесс сразу.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures::channel::oneshot;
+use tide::{Middleware, Next, Request};
+
+// Код выхода при повторном сигнале, как у процесса, прерванного SIGINT.
+const FORCED_EXIT_CODE: i32 = 130;
+
+#[derive(Clone)]
+pub struct Shutdown
+{
+    in_flight: Arc<AtomicUsize>,
+    stopping: Arc<AtomicBool>,
+}
+
+// Уменьшает счетчик и тогда, когда обработчик запроса паникует или его future отменяется.
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard
+{
+    fn drop(&mut self)
+    {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shutdown
+{
+    pub fn new() -> Shutdown
+    {
+        Shutdown {
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            stopping: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn begin(&self)
+    {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    pub fn in_flight(&self) -> usize
+    {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    // Ждет завершения начатых запросов. Возвращает, сколько запросов не успело завершиться.
+    pub fn wait(&self, timeout: Duration) -> usize
+    {
+        let started = Instant::now();
+        while self.in_flight() > 0 && started.elapsed() < timeout
+        {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        self.in_flight()
+    }
+}
+
+// Future, который завершается при первом SIGINT или SIGTERM.
+pub fn signal() -> oneshot::Receiver<()>
+{
+    let (sender, receiver) = oneshot::channel();
+    let sender = Mutex::new(Some(sender));
+    ctrlc::set_handler(move || match sender.lock().unwrap().take()
+    {
+        Some(sender) =>
+        {
+            let _ = sender.send(());
+        },
+        None =>
+        {
+            eprintln!("second signal received, exiting without waiting for requests");
+            std::process::exit(FORCED_EXIT_CODE);
+        },
+    })
+    .expect("failed to install signal handler");
+    receiver
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Shutdown
+{
+    async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> tide::Result
+    {
+        // Счетчик увеличивается до проверки флага, чтобы wait не пропустил запрос, начатый одновременно с сигналом.
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let _guard = InFlightGuard(self.in_flight.clone());
+        if self.stopping.load(Ordering::SeqCst)
+        {
+            let mut response = crate::response_error_with_status(503, "server is shutting down");
+            response.insert_header("Connection", "close");
+            return Ok(response);
+        }
+        Ok(next.run(request).await)
+    }
+}
@@ -0,0 +1,346 @@
+// Хранение данных сервера между перезапусками.
+//
+// Бэкенд `memory` ничего не сохраняет. Бэкенд `file` держит всю базу в одном JSON файле:
+// читает его при старте и перезаписывает после каждого изменяющего запроса. В файле вместе
+// с базой записана версия ее формата. Сервер читает только файлы текущей версии, файлы
+// старых версий обновляет команда `migrate`.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::json;
+use tide::http::Method;
+use tide::{Middleware, Next, Request};
+use crate::config::{Backend, StorageConfig};
+use crate::DataBase;
+
+// Версия формата сохраненной базы. Увеличивается при несовместимых изменениях DataBase.
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Serialize)]
+struct Saved<'a>
+{
+    schema_version: u32,
+    data: &'a DataBase,
+}
+
+pub enum Storage
+{
+    Memory,
+    File(PathBuf),
+}
+
+impl Storage
+{
+    pub fn new(config: &StorageConfig) -> Storage
+    {
+        match config.backend
+        {
+            Backend::Memory => Storage::Memory,
+            Backend::File => Storage::File(config.path.clone()),
+        }
+    }
+
+    // Сохраненная база или None, если сохранять некуда или файла еще нет.
+    pub fn load(&self) -> io::Result<Option<DataBase>>
+    {
+        match self.load_any()?
+        {
+            Some((version, _)) if version != SCHEMA_VERSION =>
+            {
+                let msg = format!("{} has schema version {}, run `secret-santa-service migrate` first", self.describe(), version);
+                Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+            },
+            loaded => Ok(loaded.map(|(_, data)| data)),
+        }
+    }
+
+    // Как load, но принимает и старые версии формата. Возвращает версию файла и обновленную базу.
+    pub fn load_any(&self) -> io::Result<Option<(u32, DataBase)>>
+    {
+        match self
+        {
+            Storage::Memory => Ok(None),
+            Storage::File(path) =>
+            {
+                let bytes = match std::fs::read(path)
+                {
+                    Ok(bytes) => bytes,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+                    Err(err) => return Err(err),
+                };
+                decode(&bytes)
+                    .map(Some)
+                    .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, format!("failed to read {}: {}", path.display(), msg)))
+            },
+        }
+    }
+
+    // Где лежит база, для сообщений.
+    pub fn describe(&self) -> String
+    {
+        match self
+        {
+            Storage::Memory => "memory storage".to_string(),
+            Storage::File(path) => path.display().to_string(),
+        }
+    }
+
+    pub fn save(&self, data: &DataBase) -> io::Result<()>
+    {
+        match self
+        {
+            Storage::Memory => Ok(()),
+            Storage::File(path) =>
+            {
+                let bytes = encode(data);
+                // Сначала во временный файл, чтобы при сбое не остаться с наполовину записанной базой.
+                let mut tmp_path = path.clone().into_os_string();
+                tmp_path.push(".tmp");
+                std::fs::write(&tmp_path, bytes)?;
+                std::fs::rename(&tmp_path, path)
+            },
+        }
+    }
+
+    // Можно ли сейчас сохранить базу: для файла пробует записать и удалить соседний файл.
+    pub fn check_writable(&self) -> io::Result<()>
+    {
+        match self
+        {
+            Storage::Memory => Ok(()),
+            Storage::File(path) =>
+            {
+                let mut probe_path = path.clone().into_os_string();
+                probe_path.push(".probe");
+                std::fs::write(&probe_path, b"")?;
+                std::fs::remove_file(&probe_path)
+            },
+        }
+    }
+}
+
+// База в том виде, в котором она лежит в файле.
+pub fn encode(data: &DataBase) -> Vec<u8>
+{
+    serde_json::to_vec(&Saved { schema_version: SCHEMA_VERSION, data }).unwrap()
+}
+
+// Разбирает сохраненную базу любой поддерживаемой версии. Возвращает версию и обновленную базу.
+pub fn decode(bytes: &[u8]) -> Result<(u32, DataBase), String>
+{
+    let mut saved: serde_json::Value = serde_json::from_slice(bytes).map_err(|err| err.to_string())?;
+    // Файлы первых версий сервера записаны без версии формата, в них только база. Это версия 0.
+    let (version, data) = match saved.get("schema_version").map(|version| version.as_u64())
+    {
+        None => (0, saved),
+        Some(Some(version)) if version <= SCHEMA_VERSION as u64 => (version as u32, saved["data"].take()),
+        Some(_) => return Err(format!("unsupported schema version {}, expected at most {}", saved["schema_version"], SCHEMA_VERSION)),
+    };
+    let data = upgrade(version, data)?;
+    serde_json::from_value(data).map(|data| (version, data)).map_err(|err| err.to_string())
+}
+
+// Доводит данные версии `version` до SCHEMA_VERSION, по одному шагу на версию.
+fn upgrade(mut version: u32, mut data: serde_json::Value) -> Result<serde_json::Value, String>
+{
+    while version < SCHEMA_VERSION
+    {
+        data = match version
+        {
+            // В версии 1 поменялась только обертка файла, сама база та же.
+            0 => data,
+            // В версии 2 появились организации: все прежние записи попадают в организацию по умолчанию.
+            1 => add_default_organization(data)?,
+            _ => return Err(format!("no migration from schema version {}", version)),
+        };
+        version += 1;
+    }
+    Ok(data)
+}
+
+fn add_default_organization(mut data: serde_json::Value) -> Result<serde_json::Value, String>
+{
+    let object = data.as_object_mut().ok_or("database is not an object")?;
+    for (table, fields) in [("users", json!({"organization_id": 0, "is_org_admin": false})), ("groups", json!({"organization_id": 0}))]
+    {
+        let records = object.get_mut(table).and_then(|records| records.as_object_mut()).ok_or(format!("{} is not an object", table))?;
+        for record in records.values_mut()
+        {
+            let record = record.as_object_mut().ok_or(format!("{} has a record that is not an object", table))?;
+            record.extend(fields.as_object().unwrap().clone());
+        }
+    }
+    object.insert("organizations".to_string(), json!({"0": {"name": "default", "revision": 1}}));
+    object.insert("organizations_max_id".to_string(), json!(1));
+    Ok(data)
+}
+
+// Сохраняет базу после каждого изменяющего запроса. Через него же сохраняют базу планировщик
+// и остановка сервера.
+#[derive(Clone)]
+pub struct Persist
+{
+    storage: Arc<Storage>,
+    // Сохранения идут по очереди, чтобы старый снимок не записался поверх нового.
+    saving: Arc<Mutex<()>>,
+}
+
+impl Persist
+{
+    pub fn new(storage: Arc<Storage>) -> Persist
+    {
+        Persist { storage, saving: Arc::new(Mutex::new(())) }
+    }
+
+    // Сохраняет текущее состояние базы.
+    pub fn save(&self, state: &Mutex<DataBase>) -> io::Result<()>
+    {
+        let _saving = self.saving.lock().unwrap();
+        let snapshot = state.lock().unwrap().clone();
+        self.storage.save(&snapshot)
+    }
+}
+
+#[tide::utils::async_trait]
+impl Middleware<Arc<Mutex<DataBase>>> for Persist
+{
+    async fn handle(&self, request: Request<Arc<Mutex<DataBase>>>, next: Next<'_, Arc<Mutex<DataBase>>>) -> tide::Result
+    {
+        if matches!(request.method(), Method::Get | Method::Head) || matches!(*self.storage, Storage::Memory)
+        {
+            return Ok(next.run(request).await);
+        }
+        let state = request.state().clone();
+        let response = next.run(request).await;
+
+        if let Err(err) = self.save(&state)
+        {
+            tide::log::error!("failed to save state: {}", err);
+        }
+        Ok(response)
+    }
+}
+
+// Сериализация HashMap с составными ключами списком пар: ключи JSON объекта могут быть только строками.
+pub mod as_pairs
+{
+    use super::*;
+
+    pub fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let pairs: Vec<(K, V)> = Vec::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use serde_json::{json, Value};
+    use super::{decode, encode, SCHEMA_VERSION};
+    use std::collections::HashSet;
+    use crate::{Access, DataBase, Group, Permission, UserGroupId, UserGroupProps, DEFAULT_ORGANIZATION};
+
+    // База текущей версии: пользователь-владелец группы. Во множествах не больше одного элемента,
+    // иначе порядок в JSON меняется от запуска к запуску и записи нельзя сравнить.
+    fn sample() -> DataBase
+    {
+        let mut db = DataBase::new();
+        let (user_id, _) = crate::create_user(&mut db, "Danis".to_string(), DEFAULT_ORGANIZATION).unwrap();
+        let mut group = Group::new(DEFAULT_ORGANIZATION);
+        group.admin_permissions = HashSet::from([Permission::Draw]);
+        db.groups.insert(0, group);
+        db.groups_max_id = 1;
+        db.user_groups.insert(UserGroupId { user_id, group_id: 0 }, UserGroupProps::new(Access::Owner));
+        db
+    }
+
+    fn to_value(db: &DataBase) -> Value
+    {
+        serde_json::from_slice(&encode(db)).unwrap()
+    }
+
+    // Та же база, какой ее записала версия 1: без организаций.
+    fn without_organizations(db: &DataBase) -> Value
+    {
+        let mut data = to_value(db)["data"].take();
+        let object = data.as_object_mut().unwrap();
+        object.remove("organizations");
+        object.remove("organizations_max_id");
+        for user in object["users"].as_object_mut().unwrap().values_mut()
+        {
+            user.as_object_mut().unwrap().remove("organization_id");
+            user.as_object_mut().unwrap().remove("is_org_admin");
+        }
+        for group in object["groups"].as_object_mut().unwrap().values_mut()
+        {
+            group.as_object_mut().unwrap().remove("organization_id");
+        }
+        data
+    }
+
+    #[test]
+    fn current_version_round_trip()
+    {
+        let db = sample();
+        let (version, decoded) = decode(&encode(&db)).unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        assert_eq!(to_value(&decoded), to_value(&db));
+    }
+
+    #[test]
+    fn upgrades_version_0()
+    {
+        let db = sample();
+        let bytes = serde_json::to_vec(&without_organizations(&db)).unwrap();
+        let (version, decoded) = decode(&bytes).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(to_value(&decoded), to_value(&db));
+    }
+
+    #[test]
+    fn upgrades_version_1()
+    {
+        let db = sample();
+        let bytes = serde_json::to_vec(&json!({"schema_version": 1, "data": without_organizations(&db)})).unwrap();
+        let (version, decoded) = decode(&bytes).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(decoded.users[&0].organization_id, DEFAULT_ORGANIZATION);
+        assert_eq!(decoded.organizations[&DEFAULT_ORGANIZATION].name, "default");
+        // Обновленная база сохраняется и читается уже как текущая версия.
+        let (version, reloaded) = decode(&encode(&decoded)).unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        assert_eq!(to_value(&reloaded), to_value(&db));
+    }
+
+    #[test]
+    fn rejects_newer_version()
+    {
+        let bytes = serde_json::to_vec(&json!({"schema_version": SCHEMA_VERSION + 1, "data": {}})).unwrap();
+        assert!(decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_broken_version_1()
+    {
+        let bytes = serde_json::to_vec(&json!({"schema_version": 1, "data": {"users": [], "groups": {}}})).unwrap();
+        assert_eq!(decode(&bytes).err().unwrap(), "users is not an object");
+    }
+}